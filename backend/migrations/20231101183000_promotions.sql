@@ -0,0 +1,22 @@
+CREATE TYPE discount_type AS ENUM ('percentage', 'fixed');
+
+CREATE TABLE promotions (
+    id BIGINT UNIQUE GENERATED ALWAYS AS IDENTITY,
+    name VARCHAR(255) NOT NULL,
+    discount_type discount_type NOT NULL,
+    -- percent for percentage discounts, cents for fixed discounts
+    discount_value BIGINT NOT NULL CHECK (discount_value >= 0),
+    -- scope, NULL means any product/product type
+    product_id BIGINT REFERENCES products(id) ON DELETE CASCADE,
+    product_type product_type,
+    -- ISO weekdays (1 = monday), empty means every day
+    weekdays INT[] NOT NULL DEFAULT '{}',
+    start_time TIME,
+    end_time TIME,
+    valid_from TIMESTAMP,
+    valid_until TIMESTAMP
+);
+
+ALTER TABLE purchases
+    ADD COLUMN promotion_id BIGINT REFERENCES promotions(id) ON DELETE SET NULL,
+    ADD COLUMN discount BIGINT NOT NULL DEFAULT 0;
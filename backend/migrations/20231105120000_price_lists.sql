@@ -0,0 +1,23 @@
+CREATE TABLE price_lists (
+    id BIGINT UNIQUE GENERATED ALWAYS AS IDENTITY,
+    name VARCHAR(255) NOT NULL UNIQUE
+);
+
+CREATE TABLE price_list_entries (
+    price_list_id BIGINT NOT NULL REFERENCES price_lists(id) ON DELETE CASCADE,
+    product_id BIGINT NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    price BIGINT NOT NULL,
+    PRIMARY KEY(price_list_id, product_id)
+);
+
+CREATE TABLE account_groups (
+    id BIGINT UNIQUE GENERATED ALWAYS AS IDENTITY,
+    name VARCHAR(255) NOT NULL UNIQUE,
+    price_list_id BIGINT REFERENCES price_lists(id) ON DELETE SET NULL
+);
+
+ALTER TABLE accounts
+    ADD COLUMN group_id BIGINT REFERENCES account_groups(id) ON DELETE SET NULL;
+
+ALTER TABLE purchases
+    ADD COLUMN price_list_id BIGINT REFERENCES price_lists(id) ON DELETE SET NULL;
@@ -0,0 +1,23 @@
+CREATE TABLE deposit_types (
+    id BIGINT UNIQUE GENERATED ALWAYS AS IDENTITY,
+    name VARCHAR(255) NOT NULL UNIQUE,
+    amount BIGINT NOT NULL CHECK (amount >= 0)
+);
+
+ALTER TABLE products
+    ADD COLUMN deposit_type_id BIGINT REFERENCES deposit_types(id) ON DELETE SET NULL;
+
+ALTER TABLE purchases
+    ADD COLUMN deposit_type_id BIGINT REFERENCES deposit_types(id) ON DELETE SET NULL,
+    -- total deposit charged on top of paid_price
+    ADD COLUMN deposit BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE deposit_returns (
+    id BIGINT UNIQUE GENERATED ALWAYS AS IDENTITY,
+    account_id VARCHAR(255) NOT NULL REFERENCES accounts(id),
+    deposit_type_id BIGINT NOT NULL REFERENCES deposit_types(id),
+    quantity INT NOT NULL CHECK (quantity > 0),
+    -- total amount credited to the account
+    amount BIGINT NOT NULL,
+    returned_at TIMESTAMP NOT NULL DEFAULT now()
+);
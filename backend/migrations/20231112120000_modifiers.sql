@@ -0,0 +1,24 @@
+CREATE TABLE modifier_groups (
+    id BIGINT UNIQUE GENERATED ALWAYS AS IDENTITY,
+    product_id BIGINT NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    min_selected INT NOT NULL DEFAULT 0,
+    max_selected INT NOT NULL DEFAULT 1,
+    CHECK (0 <= min_selected AND min_selected <= max_selected)
+);
+
+CREATE TABLE modifier_options (
+    id BIGINT UNIQUE GENERATED ALWAYS AS IDENTITY,
+    group_id BIGINT NOT NULL REFERENCES modifier_groups(id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    -- added to the unit price, negative for discounts like "own mug"
+    price_delta BIGINT NOT NULL DEFAULT 0
+);
+
+-- name and price_delta are copied, so the history survives changes to the options
+CREATE TABLE purchase_modifiers (
+    purchase_id BIGINT NOT NULL REFERENCES purchases(id) ON DELETE CASCADE,
+    modifier_option_id BIGINT REFERENCES modifier_options(id) ON DELETE SET NULL,
+    name VARCHAR(255) NOT NULL,
+    price_delta BIGINT NOT NULL
+);
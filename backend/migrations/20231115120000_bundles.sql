@@ -0,0 +1,18 @@
+-- NULL means the stock of this product isn't tracked
+ALTER TABLE products ADD COLUMN stock INT;
+
+CREATE TABLE bundle_components (
+    bundle_id BIGINT NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    product_id BIGINT NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    quantity INT NOT NULL DEFAULT 1 CHECK (quantity > 0),
+    PRIMARY KEY(bundle_id, product_id),
+    CHECK (bundle_id <> product_id)
+);
+
+-- the components consumed by a bundle purchase, copied at purchase time
+CREATE TABLE purchase_components (
+    purchase_id BIGINT NOT NULL REFERENCES purchases(id) ON DELETE CASCADE,
+    product_id BIGINT NOT NULL REFERENCES products(id),
+    quantity INT NOT NULL,
+    PRIMARY KEY(purchase_id, product_id)
+);
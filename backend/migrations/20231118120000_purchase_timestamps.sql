@@ -0,0 +1,3 @@
+ALTER TABLE purchases ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT now();
+
+CREATE INDEX purchases_created_at_idx ON purchases(created_at);
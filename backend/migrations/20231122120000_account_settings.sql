@@ -0,0 +1,7 @@
+CREATE TYPE leaderboard_visibility AS ENUM ('visible', 'anonymous', 'hidden');
+
+-- accounts without a row here use the defaults
+CREATE TABLE account_settings (
+    account_id VARCHAR(255) NOT NULL PRIMARY KEY REFERENCES accounts(id),
+    leaderboard_visibility leaderboard_visibility NOT NULL DEFAULT 'visible'
+);
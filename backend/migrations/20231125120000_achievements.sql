@@ -0,0 +1,22 @@
+CREATE TYPE achievement_rule AS ENUM ('purchasecount', 'allproductstried', 'purchasebefore');
+
+CREATE TABLE achievements (
+    id BIGINT UNIQUE GENERATED ALWAYS AS IDENTITY,
+    name VARCHAR(255) NOT NULL,
+    description VARCHAR(1024) NOT NULL DEFAULT '',
+    rule achievement_rule NOT NULL,
+    -- amount of purchases needed, for purchasecount and purchasebefore
+    threshold INT NOT NULL DEFAULT 1 CHECK (threshold > 0),
+    -- scope, NULL means any product/product type
+    product_id BIGINT REFERENCES products(id) ON DELETE CASCADE,
+    product_type product_type,
+    -- for purchasebefore, wall clock time in the statistics timezone
+    before_time TIME
+);
+
+CREATE TABLE account_achievements (
+    account_id VARCHAR(255) NOT NULL REFERENCES accounts(id),
+    achievement_id BIGINT NOT NULL REFERENCES achievements(id) ON DELETE CASCADE,
+    awarded_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY(account_id, achievement_id)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE purchases ADD COLUMN refunded_at TIMESTAMP;
+
+-- we don't know when older purchases were refunded, the purchase time is the best guess
+UPDATE purchases SET refunded_at = created_at WHERE refunded;
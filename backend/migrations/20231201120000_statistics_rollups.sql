@@ -0,0 +1,18 @@
+-- Daily sums of the non-refunded purchases, so statistics don't have to go through
+-- every purchase. Days are in the statistics timezone, the backend rebuilds these
+-- on startup when they are empty or were built in another timezone.
+CREATE TABLE daily_product_sales (
+    day DATE NOT NULL,
+    product_id BIGINT NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    units BIGINT NOT NULL,
+    revenue BIGINT NOT NULL,
+    PRIMARY KEY(day, product_id)
+);
+
+CREATE TABLE daily_account_sales (
+    day DATE NOT NULL,
+    account_id VARCHAR(255) NOT NULL REFERENCES accounts(id),
+    product_type product_type NOT NULL,
+    units BIGINT NOT NULL,
+    PRIMARY KEY(day, account_id, product_type)
+);
@@ -0,0 +1,13 @@
+-- The picture gallery of a product, ordered by position. The first image is the
+-- primary one, products.picture always holds its key for older clients.
+CREATE TABLE product_images (
+    id BIGINT UNIQUE GENERATED ALWAYS AS IDENTITY,
+    product_id BIGINT NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    picture VARCHAR(255) NOT NULL,
+    position INT NOT NULL,
+    -- deferred, so a reorder can swap positions within one statement
+    UNIQUE(product_id, position) DEFERRABLE INITIALLY DEFERRED
+);
+
+INSERT INTO product_images (product_id, picture, position)
+SELECT id, picture, 0 FROM products WHERE picture IS NOT NULL;
@@ -0,0 +1,18 @@
+-- Whether a promotion is running at a wall clock time. The daily window may wrap
+-- around midnight, a window starting and ending at the same time lasts all day.
+CREATE FUNCTION promotion_active_at(promotion promotions, at TIMESTAMP) RETURNS BOOLEAN
+LANGUAGE SQL IMMUTABLE AS $$
+    SELECT (promotion.valid_from IS NULL OR promotion.valid_from <= at)
+        AND (promotion.valid_until IS NULL OR at < promotion.valid_until)
+        AND (cardinality(promotion.weekdays) = 0
+            OR EXTRACT(ISODOW FROM at)::INT = ANY(promotion.weekdays))
+        AND CASE
+            WHEN promotion.start_time IS NULL AND promotion.end_time IS NULL THEN true
+            WHEN promotion.end_time IS NULL THEN promotion.start_time <= at::TIME
+            WHEN promotion.start_time IS NULL THEN at::TIME < promotion.end_time
+            WHEN promotion.start_time < promotion.end_time
+                THEN promotion.start_time <= at::TIME AND at::TIME < promotion.end_time
+            WHEN promotion.start_time = promotion.end_time THEN true
+            ELSE promotion.start_time <= at::TIME OR at::TIME < promotion.end_time
+        END
+$$;
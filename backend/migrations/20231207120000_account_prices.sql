@@ -0,0 +1,19 @@
+-- The price list entry an account gets for a product through its group, if any
+CREATE FUNCTION account_price_list_entry(for_account VARCHAR, for_product BIGINT)
+RETURNS SETOF price_list_entries LANGUAGE SQL STABLE AS $$
+    SELECT price_list_entries.* FROM price_list_entries
+    JOIN account_groups ON account_groups.price_list_id = price_list_entries.price_list_id
+    JOIN accounts ON accounts.group_id = account_groups.id
+    WHERE accounts.id = for_account AND price_list_entries.product_id = for_product
+$$;
+
+-- The unit price an account pays for a product, the base price without a price list entry
+CREATE FUNCTION account_price(for_account VARCHAR, for_product BIGINT)
+RETURNS BIGINT LANGUAGE SQL STABLE AS $$
+    SELECT COALESCE(
+        (SELECT price FROM account_price_list_entry(for_account, for_product)),
+        products.price
+    )
+    FROM products
+    WHERE products.id = for_product
+$$;
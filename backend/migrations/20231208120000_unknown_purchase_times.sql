@@ -0,0 +1,27 @@
+-- purchase_timestamps gave every purchase made before it the time it ran, which put
+-- all older sales on a single day. Those times are unknown instead now, and purchases
+-- without one are left out of everything that goes by time. refund_timestamps copied
+-- the same time to their refunds. The rows are found by the time sqlx recorded for
+-- the migration, which ran in the same transaction and so got the same now().
+ALTER TABLE purchases ALTER COLUMN created_at DROP NOT NULL;
+
+DO $$
+DECLARE
+    migrated_at TIMESTAMP;
+BEGIN
+    -- databases set up without sqlx have nothing to go by
+    IF to_regclass('_sqlx_migrations') IS NULL THEN
+        RETURN;
+    END IF;
+
+    SELECT installed_on::TIMESTAMP INTO migrated_at
+    FROM _sqlx_migrations
+    WHERE version = 20231118120000;
+
+    UPDATE purchases SET refunded_at = NULL WHERE refunded_at = migrated_at;
+    UPDATE purchases SET created_at = NULL WHERE created_at = migrated_at;
+END
+$$;
+
+DELETE FROM daily_product_sales;
+DELETE FROM daily_account_sales;
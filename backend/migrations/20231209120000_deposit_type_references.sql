@@ -0,0 +1,11 @@
+-- Deposit types charged by a purchase or returned can't be deleted, deleting them
+-- would erase the deposits still owed or fail on the returns with a raw FK error
+ALTER TABLE purchases
+    DROP CONSTRAINT purchases_deposit_type_id_fkey,
+    ADD CONSTRAINT purchases_deposit_type_id_fkey
+        FOREIGN KEY (deposit_type_id) REFERENCES deposit_types(id) ON DELETE RESTRICT;
+
+ALTER TABLE deposit_returns
+    DROP CONSTRAINT deposit_returns_deposit_type_id_fkey,
+    ADD CONSTRAINT deposit_returns_deposit_type_id_fkey
+        FOREIGN KEY (deposit_type_id) REFERENCES deposit_types(id) ON DELETE RESTRICT;
@@ -0,0 +1,6 @@
+-- The timezone the rollups were built in, a single row once they were built.
+-- Existing rollups have none, so they are rebuilt once.
+CREATE TABLE rollup_meta (
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    timezone TEXT NOT NULL
+);
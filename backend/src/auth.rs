@@ -1,6 +1,6 @@
-use std::sync::OnceLock;
+use std::{str::FromStr, sync::OnceLock};
 
-use jsonwebtoken::{jwk::JwkSet, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use once_cell::sync::Lazy;
 use poem::{
     error::{BadRequest, InternalServerError, Unauthorized},
@@ -44,7 +44,8 @@ pub async fn check_bearer(bearer: Bearer) -> Result<UserClaims> {
     let decoding_key = DecodingKey::from_jwk(jwk).map_err(InternalServerError)?;
     let algorithm = jwk
         .common
-        .algorithm
+        .key_algorithm
+        .and_then(|key_algorithm| Algorithm::from_str(&key_algorithm.to_string()).ok())
         .ok_or(poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
     let validation = Validation::new(algorithm);
 
@@ -65,6 +66,11 @@ pub struct UserClaims {
     pub email: String,
     #[serde(default)]
     pub groups: Vec<String>,
+    /// Url of the profile picture, if the identity provider has one
+    #[serde(default)]
+    pub picture: Option<String>,
+    /// When the token expires, in seconds since the epoch
+    pub exp: i64,
 }
 
 static ADMIN_GROUP: Lazy<String> = Lazy::new(|| SETTINGS.get_string("auth.admin_group").unwrap());
@@ -121,7 +127,7 @@ static PIN_JWT_KEY: Lazy<EncodingKey> = Lazy::new(get_jwt_encoding_key);
 fn get_jwt_encoding_key() -> EncodingKey {
     let key_path = SETTINGS.get_string("auth.pinlogin.keypath").unwrap();
     let key = std::fs::read(key_path.clone())
-        .expect(format!("You need to put a private pem key at {key_path}").as_str());
+        .unwrap_or_else(|_| panic!("You need to put a private pem key at {key_path}"));
     trace!(
         "Read private key pem file: {}",
         String::from_utf8(key.clone()).unwrap()
@@ -136,7 +142,7 @@ pub fn create_pin_jwt(user_id: &str) -> jsonwebtoken::errors::Result<String> {
         user_id: user_id.to_owned(),
     };
 
-    let jwt = jsonwebtoken::encode(&header, &claims, &*PIN_JWT_KEY)?;
+    let jwt = jsonwebtoken::encode(&header, &claims, &PIN_JWT_KEY)?;
 
     Ok(format!("Pin {jwt}"))
 }
@@ -1,49 +1,152 @@
 use core::panic;
 
 use async_graphql::{InputObject, SimpleObject};
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, NaiveTime};
 use sqlx::FromRow;
 
+use crate::picture::{thumbnail_key, MAX_SIZE, THUMBNAIL_SIZES};
+
 /// Currently a BIGINT
 pub type PrimaryKey = i64;
 
 #[derive(SimpleObject, InputObject)]
-#[graphql(input_name = "AccountInput")]
+#[graphql(input_name = "AccountInput", complex)]
 pub struct Account {
     pub id: String,
     pub name: String,
     pub email: String,
+    /// The partial key, exposed as a `Picture`
+    #[graphql(skip_output)]
     pub picture: Option<String>,
     pub deleted_at: Option<NaiveDateTime>,
     #[graphql(secret)]
     pub pin_hash: String,
     pub balance: i64,
+    pub group_id: Option<PrimaryKey>,
+}
+
+#[derive(SimpleObject, Clone)]
+pub struct BalanceChange {
+    pub account_id: String,
+    /// The balance after the change
+    pub balance: i64,
+}
+
+/// An uploaded picture, stored in its full size and a few scaled down copies.
+/// The keys are partial, just like the `picture` columns.
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Picture {
+    pub key: String,
+    /// Smallest first
+    pub sizes: Vec<PictureSize>,
+    #[graphql(skip)]
+    pub full_key: String,
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct PictureSize {
+    /// Longest edge in pixels, smaller pictures aren't scaled up
+    pub size: u32,
+    pub key: String,
+    #[graphql(skip)]
+    pub full_key: String,
 }
 
-#[derive(SimpleObject, InputObject, FromRow)]
-#[graphql(input_name = "ProductInput")]
+impl Picture {
+    /// `full_key` turns a partial key into the account or product specific one
+    pub fn new(key: String, full_key: fn(&str) -> String) -> Picture {
+        let sizes = THUMBNAIL_SIZES
+            .iter()
+            .map(|&size| (size, thumbnail_key(&key, size)))
+            .chain(std::iter::once((MAX_SIZE, key.clone())))
+            .map(|(size, key)| PictureSize {
+                size,
+                full_key: full_key(&key),
+                key,
+            })
+            .collect();
+        Picture {
+            full_key: full_key(&key),
+            key,
+            sizes,
+        }
+    }
+}
+
+#[derive(SimpleObject, InputObject, Default)]
+#[graphql(input_name = "AccountSettingsInput")]
+pub struct AccountSettings {
+    #[graphql(default)]
+    pub leaderboard_visibility: LeaderboardVisibility,
+}
+
+#[derive(async_graphql::Enum, sqlx::Type, Copy, Clone, Debug, Eq, PartialEq, Default)]
+#[sqlx(type_name = "leaderboard_visibility", rename_all = "lowercase")]
+pub enum LeaderboardVisibility {
+    #[default]
+    Visible,
+    /// Shown without name and picture
+    Anonymous,
+    Hidden,
+}
+
+#[derive(SimpleObject, InputObject, FromRow, Clone)]
+#[graphql(input_name = "ProductInput", complex)]
 pub struct Product {
     #[graphql(default = -1)]
     pub id: PrimaryKey,
     pub name: String,
     pub product_type: ProductType,
     pub price: i64,
+    /// The partial key, exposed as a `Picture`
+    #[graphql(skip_output)]
     pub picture: Option<String>,
+    /// The bottle deposit charged on top of `price`, if any
+    pub deposit_type_id: Option<PrimaryKey>,
+    /// `None` if the stock of this product isn't tracked
+    pub stock: Option<i32>,
+}
+
+/// A product was created, changed or deleted
+#[derive(SimpleObject, Clone)]
+pub struct ProductUpdate {
+    pub id: PrimaryKey,
+    /// `None` if the product was deleted
+    pub product: Option<Product>,
 }
 
 #[derive(SimpleObject, sqlx::FromRow)]
+#[graphql(complex)]
 pub struct ProductWithFavorite {
     #[graphql(default = -1)]
     pub id: PrimaryKey,
     pub name: String,
     pub product_type: ProductType,
     pub price: i64,
+    /// Deposit charged on top of `price`
+    pub deposit: i64,
+    /// The partial key, exposed as a `Picture`
+    #[graphql(skip_output)]
     pub picture: Option<String>,
     #[sqlx(default)]
     pub is_favorite: bool,
 }
 
-#[derive(async_graphql::Enum, sqlx::Type, Copy, Clone, Eq, PartialEq)]
+/// One image of a product's gallery, the first one is the product's `picture`
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct ProductImage {
+    pub id: PrimaryKey,
+    pub product_id: PrimaryKey,
+    /// The partial key, exposed as a `Picture`
+    #[graphql(skip)]
+    pub picture: String,
+    pub position: i32,
+}
+
+#[derive(async_graphql::Enum, sqlx::Type, Copy, Clone, Debug, Eq, PartialEq)]
 #[sqlx(type_name = "product_type", rename_all = "lowercase")]
 pub enum ProductType {
     HotDrink,
@@ -61,8 +164,8 @@ impl From<i16> for ProductType {
     }
 }
 
-#[derive(SimpleObject, InputObject)]
-#[graphql(input_name = "PurchaseInput")]
+#[derive(SimpleObject, InputObject, Clone)]
+#[graphql(input_name = "PurchaseInput", complex)]
 pub struct Purchase {
     pub id: PrimaryKey,
     pub account_id: String,
@@ -70,6 +173,183 @@ pub struct Purchase {
     pub paid_price: i64,
     pub quantity: i32,
     pub refunded: bool,
+    pub promotion_id: Option<PrimaryKey>,
+    /// Total discount granted by the promotion, already subtracted from `paid_price`
+    pub discount: i64,
+    /// The price list the price came from, `None` if the base price was paid
+    pub price_list_id: Option<PrimaryKey>,
+    pub deposit_type_id: Option<PrimaryKey>,
+    /// Total deposit charged on top of `paid_price`
+    pub deposit: i64,
+    /// `None` for purchases made before purchase times were recorded
+    pub created_at: Option<NaiveDateTime>,
+    pub refunded_at: Option<NaiveDateTime>,
+}
+
+#[derive(SimpleObject, InputObject)]
+#[graphql(input_name = "ModifierGroupInput", complex)]
+pub struct ModifierGroup {
+    #[graphql(default = -1)]
+    pub id: PrimaryKey,
+    pub product_id: PrimaryKey,
+    pub name: String,
+    /// How many options of this group have to be selected at least
+    #[graphql(default)]
+    pub min_selected: i32,
+    /// How many options of this group can be selected at most
+    #[graphql(default = 1)]
+    pub max_selected: i32,
+}
+
+#[derive(SimpleObject, InputObject)]
+#[graphql(input_name = "ModifierOptionInput")]
+pub struct ModifierOption {
+    #[graphql(default = -1)]
+    pub id: PrimaryKey,
+    pub group_id: PrimaryKey,
+    pub name: String,
+    /// Added to the unit price, negative for discounts
+    pub price_delta: i64,
+}
+
+/// A modifier option as it was chosen for a purchase
+#[derive(SimpleObject)]
+pub struct PurchaseModifier {
+    pub purchase_id: PrimaryKey,
+    /// `None` if the option has been deleted since
+    pub modifier_option_id: Option<PrimaryKey>,
+    pub name: String,
+    pub price_delta: i64,
+}
+
+#[derive(SimpleObject)]
+pub struct BundleComponent {
+    pub bundle_id: PrimaryKey,
+    pub product_id: PrimaryKey,
+    pub quantity: i32,
+}
+
+#[derive(SimpleObject)]
+pub struct PriceList {
+    pub id: PrimaryKey,
+    pub name: String,
+}
+
+#[derive(SimpleObject)]
+pub struct PriceListEntry {
+    pub price_list_id: PrimaryKey,
+    pub product_id: PrimaryKey,
+    pub price: i64,
+}
+
+#[derive(SimpleObject, InputObject)]
+#[graphql(input_name = "AccountGroupInput")]
+pub struct AccountGroup {
+    #[graphql(default = -1)]
+    pub id: PrimaryKey,
+    pub name: String,
+    /// Accounts in this group pay the prices from this list, if set
+    pub price_list_id: Option<PrimaryKey>,
+}
+
+#[derive(SimpleObject, InputObject)]
+#[graphql(input_name = "DepositTypeInput")]
+pub struct DepositType {
+    #[graphql(default = -1)]
+    pub id: PrimaryKey,
+    pub name: String,
+    pub amount: i64,
+}
+
+#[derive(SimpleObject)]
+pub struct DepositReturn {
+    pub id: PrimaryKey,
+    pub account_id: String,
+    pub deposit_type_id: PrimaryKey,
+    pub quantity: i32,
+    pub amount: i64,
+    pub returned_at: NaiveDateTime,
+}
+
+#[derive(SimpleObject, InputObject)]
+#[graphql(input_name = "PromotionInput")]
+pub struct Promotion {
+    #[graphql(default = -1)]
+    pub id: PrimaryKey,
+    pub name: String,
+    pub discount_type: DiscountType,
+    /// Percent for percentage discounts, cents for fixed discounts
+    pub discount_value: i64,
+    /// Only applies to this product, if set
+    pub product_id: Option<PrimaryKey>,
+    /// Only applies to products of this type, if set
+    pub product_type: Option<ProductType>,
+    /// ISO weekdays (1 = monday) this promotion is active on, empty means every day
+    #[graphql(default)]
+    pub weekdays: Vec<i32>,
+    /// Daily time window, may wrap around midnight.
+    /// A window starting and ending at the same time lasts all day.
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
+    pub valid_from: Option<NaiveDateTime>,
+    pub valid_until: Option<NaiveDateTime>,
+}
+
+#[derive(async_graphql::Enum, sqlx::Type, Copy, Clone, Debug, Eq, PartialEq)]
+#[sqlx(type_name = "discount_type", rename_all = "lowercase")]
+pub enum DiscountType {
+    Percentage,
+    Fixed,
+}
+
+impl Promotion {
+    /// Discount for a single unit, never more than the unit price itself
+    pub fn unit_discount(&self, unit_price: i64) -> i64 {
+        let discount = match self.discount_type {
+            DiscountType::Percentage => unit_price * self.discount_value / 100,
+            DiscountType::Fixed => self.discount_value,
+        };
+        discount.clamp(0, unit_price)
+    }
+}
+
+#[derive(SimpleObject, InputObject)]
+#[graphql(input_name = "AchievementInput")]
+pub struct Achievement {
+    #[graphql(default = -1)]
+    pub id: PrimaryKey,
+    pub name: String,
+    #[graphql(default)]
+    pub description: String,
+    pub rule: AchievementRule,
+    /// Amount of purchases needed for `PurchaseCount` and `PurchaseBefore`
+    #[graphql(default = 1)]
+    pub threshold: i32,
+    /// Only purchases of this product count, if set
+    pub product_id: Option<PrimaryKey>,
+    /// Only purchases of products of this type count, if set
+    pub product_type: Option<ProductType>,
+    /// For `PurchaseBefore`, wall clock time in the statistics timezone
+    pub before_time: Option<NaiveTime>,
+}
+
+#[derive(async_graphql::Enum, sqlx::Type, Copy, Clone, Debug, Eq, PartialEq)]
+#[sqlx(type_name = "achievement_rule", rename_all = "lowercase")]
+pub enum AchievementRule {
+    /// Bought `threshold` units, e.g. the first purchase or the 100th coffee
+    PurchaseCount,
+    /// Bought every product (of the product type) at least once
+    AllProductsTried,
+    /// Bought `threshold` units before `before_time` of a day, e.g. early birds
+    PurchaseBefore,
+}
+
+#[derive(SimpleObject)]
+pub struct AwardedAchievement {
+    pub achievement_id: PrimaryKey,
+    pub name: String,
+    pub description: String,
+    pub awarded_at: NaiveDateTime,
 }
 
 #[derive(SimpleObject)]
@@ -80,7 +360,15 @@ pub struct ProductPurchaseCount {
 }
 
 #[derive(SimpleObject)]
+#[graphql(complex)]
 pub struct AccountPurchaseCount {
-    pub id: PrimaryKey,
+    /// The account id
+    pub id: String,
+    pub name: String,
+    /// The partial key, exposed as a `Picture`
+    #[graphql(skip_output)]
+    pub picture: Option<String>,
     pub count: i32,
+    /// Id, name and picture are hidden by the account's privacy settings
+    pub anonymous: bool,
 }
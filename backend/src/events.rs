@@ -0,0 +1,65 @@
+//! In-process event bus feeding the GraphQL subscriptions. Events only reach the
+//! subscribers connected when they are published, nothing is stored or replayed.
+
+use futures_util::{stream, Stream};
+use once_cell::sync::Lazy;
+use sqlx::{Pool, Postgres};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use crate::db::{BalanceChange, PrimaryKey, Product, ProductType, ProductUpdate, Purchase};
+
+/// Events a subscriber can fall behind by before it misses some
+const CAPACITY: usize = 1024;
+
+#[derive(Clone)]
+pub enum Event {
+    BalanceChanged(BalanceChange),
+    PurchaseMade(Purchase),
+    /// Created, changed, deleted or its stock moved
+    ProductUpdated(ProductUpdate),
+}
+
+static SENDER: Lazy<broadcast::Sender<Event>> = Lazy::new(|| broadcast::channel(CAPACITY).0);
+
+pub fn publish(event: Event) {
+    // this only fails if nobody is subscribed, which is fine
+    let _ = SENDER.send(event);
+}
+
+/// Loads the product as it is now and publishes it, so the subscribers don't each
+/// have to. Failures are only logged, the change itself already went through.
+pub async fn publish_product_update(db: &Pool<Postgres>, id: PrimaryKey) {
+    let product = sqlx::query_as!(
+        Product,
+        r#"
+        SELECT id, name, price, picture, product_type as "product_type: ProductType",
+        deposit_type_id, stock
+        FROM products
+        WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_optional(db)
+    .await;
+
+    match product {
+        Ok(product) => publish(Event::ProductUpdated(ProductUpdate { id, product })),
+        Err(err) => warn!("Couldn't load product {id} to publish its update: {err}"),
+    }
+}
+
+/// Every event published from now on, until the stream is dropped
+pub fn subscribe() -> impl Stream<Item = Event> {
+    stream::unfold(SENDER.subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Subscriber fell behind, skipped {skipped} events");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
@@ -1,11 +1,21 @@
+use std::time::Duration;
+
 use async_graphql::{
-    http::GraphiQLSource, Context, EmptySubscription, ErrorExtensions, MergedObject, Schema,
+    http::{GraphiQLSource, ALL_WEBSOCKET_PROTOCOLS},
+    BatchRequest, Context, ErrorExtensions, MergedObject, MergedSubscription, Schema,
+};
+use async_graphql_poem::{
+    GraphQLBatchRequest, GraphQLBatchResponse, GraphQLProtocol, GraphQLWebSocket,
 };
-use async_graphql_poem::{GraphQLBatchRequest, GraphQLBatchResponse};
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use futures_util::{Stream, StreamExt};
 use poem::{
     handler,
+    http::StatusCode,
     web::{
         headers::{Authorization, HeaderMapExt},
+        websocket::WebSocket,
         Data, Html,
     },
     IntoResponse, Request, Result,
@@ -13,10 +23,22 @@ use poem::{
 use poem_openapi::auth::Bearer;
 use sqlx::{Pool, Postgres};
 
-use crate::auth::{check_bearer, UserClaims};
+use crate::{
+    auth::{check_bearer, UserClaims},
+    config::SETTINGS,
+    metrics::GraphQLMetrics,
+};
 
 mod account;
+mod achievement;
+mod bundle;
+mod deposit;
+mod modifier;
+mod personal_statistics;
+mod picture;
+mod price_list;
 mod product;
+mod promotion;
 mod purchase;
 mod statistics;
 mod types;
@@ -24,31 +46,91 @@ mod types;
 #[derive(MergedObject, Default)]
 pub struct QueryRoot(
     account::AccountQuery,
+    achievement::AchievementQuery,
+    bundle::BundleQuery,
+    deposit::DepositQuery,
+    modifier::ModifierQuery,
+    price_list::PriceListQuery,
     product::ProductQuery,
+    promotion::PromotionQuery,
     purchase::PurchaseQuery,
     statistics::StatisticsQuery,
+    personal_statistics::PersonalStatisticsQuery,
 );
 
 #[derive(MergedObject, Default)]
 pub struct MutationRoot(
     account::AccountMutation,
+    achievement::AchievementMutation,
+    bundle::BundleMutation,
+    deposit::DepositMutation,
+    modifier::ModifierMutation,
+    price_list::PriceListMutation,
     product::ProductMutation,
+    promotion::PromotionMutation,
     purchase::PurchaseMutation,
+    statistics::StatisticsMutation,
 );
 
+#[derive(MergedSubscription, Default)]
+pub struct SubscriptionRoot(
+    account::AccountSubscription,
+    product::ProductSubscription,
+    purchase::PurchaseSubscription,
+);
+
+pub type RuscalimatSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+/// Upper bound for the operations in one batch request, `graphql.max_batch_size`
+#[derive(Clone, Copy)]
+pub struct MaxBatchSize(pub usize);
+
+impl MaxBatchSize {
+    pub fn from_settings() -> color_eyre::Result<Self> {
+        Ok(Self(limit_setting("graphql.max_batch_size")?))
+    }
+}
+
+/// Reads one of the `graphql` limits, which can't be negative
+fn limit_setting(key: &str) -> color_eyre::Result<usize> {
+    let limit = SETTINGS.get_int(key)?;
+    usize::try_from(limit).map_err(|_| eyre!("{key} can't be negative, got {limit}"))
+}
+
+/// Built once at startup, the handlers get it through `Data`
+pub fn build_schema(db_pool: &Pool<Postgres>) -> color_eyre::Result<RuscalimatSchema> {
+    let mut builder = Schema::build(
+        QueryRoot::default(),
+        MutationRoot::default(),
+        SubscriptionRoot::default(),
+    )
+    .data(db_pool.clone())
+    .extension(GraphQLMetrics)
+    .limit_depth(limit_setting("graphql.max_depth")?)
+    .limit_complexity(limit_setting("graphql.max_complexity")?);
+
+    if !SETTINGS.get_bool("graphql.introspection")? {
+        builder = builder.disable_introspection();
+    }
+
+    Ok(builder.finish())
+}
+
 #[handler]
 pub async fn graphql_handler(
     rest_request: &Request,
     GraphQLBatchRequest(mut req): GraphQLBatchRequest,
-    Data(db_pool): Data<&Pool<Postgres>>,
+    Data(schema): Data<&RuscalimatSchema>,
+    Data(MaxBatchSize(max_batch_size)): Data<&MaxBatchSize>,
 ) -> Result<GraphQLBatchResponse> {
-    let executor = Schema::build(
-        QueryRoot::default(),
-        MutationRoot::default(),
-        EmptySubscription,
-    )
-    .data(db_pool.clone())
-    .finish();
+    if let BatchRequest::Batch(requests) = &req {
+        if requests.len() > *max_batch_size {
+            return Err(poem::Error::from_string(
+                format!("Batches can contain at most {max_batch_size} operations"),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    }
 
     let bearer = rest_request
         .headers()
@@ -67,7 +149,49 @@ pub async fn graphql_handler(
         req = req.data(user_claims_data);
     }
 
-    Ok(GraphQLBatchResponse(executor.execute_batch(req).await))
+    Ok(GraphQLBatchResponse(schema.execute_batch(req).await))
+}
+
+/// Subscriptions, over either of the GraphQL WebSocket protocols
+#[handler]
+pub async fn graphql_ws_handler(
+    protocol: GraphQLProtocol,
+    websocket: WebSocket,
+    Data(schema): Data<&RuscalimatSchema>,
+) -> impl IntoResponse {
+    let schema = schema.clone();
+    websocket
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| {
+            GraphQLWebSocket::new(stream, schema, protocol)
+                .on_connection_init(on_connection_init)
+                .serve()
+        })
+}
+
+/// Browsers can't set headers on WebSockets, so the token comes with the
+/// connection_init message instead, as `{"Authorization": "Bearer <jwt>"}`.
+/// All subscriptions need one, and they end when it expires.
+async fn on_connection_init(
+    payload: serde_json::Value,
+) -> async_graphql::Result<async_graphql::Data> {
+    let mut data = async_graphql::Data::default();
+
+    let token = payload
+        .get("Authorization")
+        .or_else(|| payload.get("authorization"))
+        .and_then(serde_json::Value::as_str)
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = token {
+        let user_claims = check_bearer(Bearer {
+            token: token.to_string(),
+        })
+        .await
+        .map_err(|err| async_graphql::Error::new(err.to_string()))?;
+        data.insert(user_claims);
+    }
+
+    Ok(data)
 }
 
 #[handler]
@@ -75,13 +199,35 @@ pub async fn graphiql_handler() -> impl IntoResponse {
     Html(
         GraphiQLSource::build()
             .endpoint("/ruscalimat/v1/graphql")
+            .subscription_endpoint("/ruscalimat/v1/graphql/ws")
             .finish(),
     )
 }
 
+/// Ends a subscription once the token it was started with expires, since a
+/// WebSocket connection only checks the token when it's opened
+pub fn until_expired<S: Stream>(
+    stream: S,
+    user_claims: &UserClaims,
+) -> impl Stream<Item = S::Item> {
+    let remaining = (user_claims.exp - Utc::now().timestamp()).max(0) as u64;
+    stream.take_until(tokio::time::sleep(Duration::from_secs(remaining)))
+}
+
 pub fn extract_user_claims<'ctx>(
     ctx: &'ctx Context<'ctx>,
 ) -> async_graphql::Result<&'ctx UserClaims> {
     ctx.data()
         .map_err(|err| err.extend_with(|_, e| e.set("code", 401)))
 }
+
+pub fn extract_admin_claims<'ctx>(
+    ctx: &'ctx Context<'ctx>,
+) -> async_graphql::Result<&'ctx UserClaims> {
+    let user_claims = extract_user_claims(ctx)?;
+    if !user_claims.is_admin() {
+        return Err(async_graphql::Error::new("Admin privileges required")
+            .extend_with(|_, e| e.set("code", 403)));
+    }
+    Ok(user_claims)
+}
@@ -1,8 +1,43 @@
-use async_graphql::{Context, ErrorExtensions, InputObject, Object, SimpleObject};
+use async_graphql::{
+    ComplexObject, Context, ErrorExtensions, InputObject, Object, Result, SimpleObject,
+    Subscription,
+};
+use futures_util::{Stream, StreamExt};
+use sqlx::{Pool, Postgres};
+use tracing::warn;
 
-use crate::{auth, db::Account};
+use crate::{
+    auth,
+    db::{
+        Account, AccountSettings, AwardedAchievement, BalanceChange, LeaderboardVisibility, Picture,
+    },
+    events::{self, Event},
+    metrics,
+    picture::{self, full_account_picture_key, import},
+};
 
-use super::{extract_user_claims, types::sort::Sort};
+use super::{achievement, extract_user_claims, types::sort::Sort, until_expired};
+
+#[ComplexObject]
+impl Account {
+    async fn picture(&self) -> Option<Picture> {
+        self.picture
+            .clone()
+            .map(|key| Picture::new(key, full_account_picture_key))
+    }
+
+    /// The uploaded picture, or a generated avatar if there is none
+    async fn avatar_url(&self) -> String {
+        picture::avatar_url(&self.id)
+    }
+
+    async fn achievements(&self, ctx: &Context<'_>) -> Result<Vec<AwardedAchievement>> {
+        let db = ctx.data()?;
+        achievement::awarded_achievements(db, &self.id)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+}
 
 #[derive(SimpleObject)]
 struct AccountsList {
@@ -79,9 +114,11 @@ impl AccountQuery {
             pin_login.id
         )
         .fetch_one(db)
-        .await?;
+        .await
+        .inspect_err(|_| metrics::PIN_LOGIN_FAILURES.inc())?;
 
         if !bcrypt::verify(pin_login.pin.to_string(), user.pin_hash.as_str())? {
+            metrics::PIN_LOGIN_FAILURES.inc();
             return Err(
                 async_graphql::Error::new("Wrong pin").extend_with(|_, e| e.set("code", 401))
             );
@@ -92,6 +129,22 @@ impl AccountQuery {
         Ok(jwt)
     }
 
+    async fn my_settings(&self, ctx: &Context<'_>) -> async_graphql::Result<AccountSettings> {
+        let user_claims = extract_user_claims(ctx)?;
+        let db = ctx.data()?;
+        let settings = sqlx::query_as!(
+            AccountSettings,
+            r#"
+            SELECT leaderboard_visibility AS "leaderboard_visibility: LeaderboardVisibility"
+            FROM account_settings WHERE account_id = $1
+            "#,
+            user_claims.user_id
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(settings.unwrap_or_default())
+    }
+
     async fn deleted_accounts(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Account>> {
         let db = ctx.data()?;
         let accounts = sqlx::query_as!(
@@ -125,7 +178,7 @@ impl AccountMutation {
 
     async fn signup(&self, ctx: &Context<'_>, pin: u16) -> async_graphql::Result<Account> {
         let user_claims = extract_user_claims(ctx)?;
-        let db = ctx.data()?;
+        let db: &Pool<Postgres> = ctx.data()?;
         let pin_hash = hash_pin(pin)?;
         let account = sqlx::query_as!(
             Account,
@@ -142,6 +195,21 @@ impl AccountMutation {
         .fetch_one(db)
         .await?;
 
+        // downloading can take a while, the picture shows up once it's done
+        let db = db.clone();
+        let (account_id, claim, email) = (
+            account.id.clone(),
+            user_claims.picture.clone(),
+            account.email.clone(),
+        );
+        tokio::spawn(async move {
+            let result =
+                import::import_account_picture(&db, &account_id, claim.as_deref(), &email).await;
+            if let Err(err) = result {
+                warn!("Importing the picture of account {account_id} failed: {err}");
+            }
+        });
+
         Ok(account)
     }
 
@@ -154,11 +222,10 @@ impl AccountMutation {
         sqlx::query!(
             r#"
             UPDATE accounts
-            SET name = $1, email = $2, picture = $3
-            WHERE id = $4"#,
+            SET name = $1, email = $2
+            WHERE id = $3"#,
             account.name,
             account.email,
-            account.picture,
             account.id
         )
         .execute(db)
@@ -166,6 +233,29 @@ impl AccountMutation {
         Ok(true)
     }
 
+    async fn update_my_settings(
+        &self,
+        ctx: &Context<'_>,
+        settings: AccountSettings,
+    ) -> async_graphql::Result<AccountSettings> {
+        let user_claims = extract_user_claims(ctx)?;
+        let db = ctx.data()?;
+        let settings = sqlx::query_as!(
+            AccountSettings,
+            r#"
+            INSERT INTO account_settings (account_id, leaderboard_visibility)
+            VALUES ($1, $2)
+            ON CONFLICT (account_id) DO UPDATE SET leaderboard_visibility = $2
+            RETURNING leaderboard_visibility AS "leaderboard_visibility: LeaderboardVisibility"
+            "#,
+            user_claims.user_id,
+            settings.leaderboard_visibility as LeaderboardVisibility
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(settings)
+    }
+
     async fn set_pin(&self, ctx: &Context<'_>, pin: u16) -> async_graphql::Result<bool> {
         let user_claims = extract_user_claims(ctx)?;
         let db = ctx.data()?;
@@ -192,3 +282,34 @@ fn hash_pin(pin: u16) -> async_graphql::Result<String> {
     bcrypt::hash(pin.to_string(), bcrypt::DEFAULT_COST)
         .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
 }
+
+#[derive(Default)]
+pub struct AccountSubscription;
+
+#[Subscription]
+impl AccountSubscription {
+    /// The new balance after every purchase, refund and deposit return of the account.
+    /// Only the account itself and admins can subscribe.
+    async fn balance_changed(
+        &self,
+        ctx: &Context<'_>,
+        account_id: String,
+    ) -> Result<impl Stream<Item = BalanceChange>> {
+        let user_claims = extract_user_claims(ctx)?;
+        if user_claims.user_id != account_id && !user_claims.is_admin() {
+            return Err(
+                async_graphql::Error::new("Only admins can follow other accounts")
+                    .extend_with(|_, e| e.set("code", 403)),
+            );
+        }
+
+        let changes = events::subscribe().filter_map(move |event| {
+            let change = match event {
+                Event::BalanceChanged(change) if change.account_id == account_id => Some(change),
+                _ => None,
+            };
+            async move { change }
+        });
+        Ok(until_expired(changes, user_claims))
+    }
+}
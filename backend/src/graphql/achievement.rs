@@ -0,0 +1,247 @@
+use async_graphql::{Context, ErrorExtensions, Object, Result};
+use sqlx::{Pool, Postgres};
+
+use crate::db::{Achievement, AchievementRule, AwardedAchievement, PrimaryKey, ProductType};
+
+use super::{extract_admin_claims, extract_user_claims, statistics::parse_timezone};
+
+#[derive(Default)]
+pub struct AchievementQuery;
+
+#[Object]
+impl AchievementQuery {
+    async fn achievements(&self, ctx: &Context<'_>) -> Result<Vec<Achievement>> {
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            Achievement,
+            r#"
+            SELECT id, name, description, rule AS "rule: AchievementRule", threshold,
+            product_id, product_type AS "product_type: ProductType", before_time
+            FROM achievements
+            ORDER BY id
+            "#
+        )
+        .fetch_all(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    /// The achievements of the logged in account, newest first
+    async fn my_achievements(&self, ctx: &Context<'_>) -> Result<Vec<AwardedAchievement>> {
+        let user_claims = extract_user_claims(ctx)?;
+        let db = ctx.data()?;
+        awarded_achievements(db, &user_claims.user_id)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+}
+
+#[derive(Default)]
+pub struct AchievementMutation;
+
+#[Object]
+impl AchievementMutation {
+    /// the field id on the input object here is ignored and optional.
+    /// Accounts which already fulfill it get the achievement with their next purchase.
+    async fn create_achievement(
+        &self,
+        ctx: &Context<'_>,
+        achievement: Achievement,
+    ) -> Result<Achievement> {
+        extract_admin_claims(ctx)?;
+        validate_achievement(&achievement)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            Achievement,
+            r#"
+            INSERT INTO achievements
+            (name, description, rule, threshold, product_id, product_type, before_time)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, name, description, rule AS "rule: AchievementRule", threshold,
+            product_id, product_type AS "product_type: ProductType", before_time
+            "#,
+            achievement.name,
+            achievement.description,
+            achievement.rule as AchievementRule,
+            achievement.threshold,
+            achievement.product_id,
+            achievement.product_type as Option<ProductType>,
+            achievement.before_time
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    /// Already awarded achievements are kept, even if the account doesn't fulfill
+    /// the new rule
+    async fn update_achievement(
+        &self,
+        ctx: &Context<'_>,
+        achievement: Achievement,
+    ) -> Result<Achievement> {
+        extract_admin_claims(ctx)?;
+        validate_achievement(&achievement)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            Achievement,
+            r#"
+            UPDATE achievements
+            SET name = $2, description = $3, rule = $4, threshold = $5,
+            product_id = $6, product_type = $7, before_time = $8
+            WHERE id = $1
+            RETURNING id, name, description, rule AS "rule: AchievementRule", threshold,
+            product_id, product_type AS "product_type: ProductType", before_time
+            "#,
+            achievement.id,
+            achievement.name,
+            achievement.description,
+            achievement.rule as AchievementRule,
+            achievement.threshold,
+            achievement.product_id,
+            achievement.product_type as Option<ProductType>,
+            achievement.before_time
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn delete_achievement(&self, ctx: &Context<'_>, id: PrimaryKey) -> Result<bool> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query!("DELETE FROM achievements WHERE id = $1", id)
+            .execute(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        Ok(true)
+    }
+}
+
+fn validate_achievement(achievement: &Achievement) -> Result<()> {
+    if achievement.threshold <= 0 {
+        return Err(async_graphql::Error::new("threshold needs to be positive"));
+    }
+    if achievement.rule == AchievementRule::PurchaseBefore && achievement.before_time.is_none() {
+        return Err(async_graphql::Error::new(
+            "before_time is needed for PurchaseBefore achievements",
+        ));
+    }
+    Ok(())
+}
+
+pub(super) async fn awarded_achievements(
+    db: &Pool<Postgres>,
+    account_id: &str,
+) -> sqlx::Result<Vec<AwardedAchievement>> {
+    sqlx::query_as!(
+        AwardedAchievement,
+        r#"
+        SELECT achievements.id AS achievement_id, achievements.name, achievements.description,
+        account_achievements.awarded_at
+        FROM account_achievements
+        JOIN achievements ON achievements.id = account_achievements.achievement_id
+        WHERE account_achievements.account_id = $1
+        ORDER BY account_achievements.awarded_at DESC, achievements.id
+        "#,
+        account_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Checks every achievement the account doesn't have yet against its non-refunded
+/// purchases and awards the fulfilled ones. Returns the newly awarded achievements.
+pub async fn award_achievements(db: &Pool<Postgres>, account_id: &str) -> Result<Vec<Achievement>> {
+    let (timezone, _) = parse_timezone(None)?;
+
+    let pending = sqlx::query_as!(
+        Achievement,
+        r#"
+        SELECT id, name, description, rule AS "rule: AchievementRule", threshold,
+        product_id, product_type AS "product_type: ProductType", before_time
+        FROM achievements
+        WHERE NOT EXISTS (
+            SELECT 1 FROM account_achievements
+            WHERE account_id = $1 AND achievement_id = achievements.id
+        )
+        "#,
+        account_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut awarded = Vec::new();
+    for achievement in pending {
+        let fulfilled = match achievement.rule {
+            AchievementRule::PurchaseCount | AchievementRule::PurchaseBefore => {
+                // purchases store the wall clock time of the database, which is
+                // converted to the statistics timezone here
+                let units = sqlx::query_scalar!(
+                    r#"
+                    SELECT COALESCE(SUM(purchases.quantity), 0)::BIGINT AS "units!"
+                    FROM purchases
+                    JOIN products ON products.id = purchases.product_id
+                    WHERE purchases.account_id = $1 AND NOT purchases.refunded
+                    AND ($2::BIGINT IS NULL OR products.id = $2)
+                    AND ($3::product_type IS NULL OR products.product_type = $3)
+                    AND ($4::TIME IS NULL
+                        OR (purchases.created_at::timestamptz AT TIME ZONE $5)::TIME < $4)
+                    "#,
+                    account_id,
+                    achievement.product_id,
+                    achievement.product_type as Option<ProductType>,
+                    achievement
+                        .before_time
+                        .filter(|_| achievement.rule == AchievementRule::PurchaseBefore),
+                    timezone
+                )
+                .fetch_one(db)
+                .await?;
+                units >= achievement.threshold as i64
+            }
+            AchievementRule::AllProductsTried => {
+                sqlx::query_scalar!(
+                    r#"
+                SELECT NOT EXISTS (
+                    SELECT 1 FROM products
+                    WHERE ($2::BIGINT IS NULL OR products.id = $2)
+                    AND ($3::product_type IS NULL OR products.product_type = $3)
+                    AND NOT EXISTS (
+                        SELECT 1 FROM purchases
+                        WHERE purchases.account_id = $1 AND NOT purchases.refunded
+                        AND purchases.product_id = products.id
+                    )
+                ) AS "fulfilled!"
+                "#,
+                    account_id,
+                    achievement.product_id,
+                    achievement.product_type as Option<ProductType>
+                )
+                .fetch_one(db)
+                .await?
+            }
+        };
+
+        if !fulfilled {
+            continue;
+        }
+
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO account_achievements (account_id, achievement_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            account_id,
+            achievement.id
+        )
+        .execute(db)
+        .await?;
+        if inserted.rows_affected() > 0 {
+            awarded.push(achievement);
+        }
+    }
+
+    Ok(awarded)
+}
@@ -0,0 +1,261 @@
+use async_graphql::{Context, ErrorExtensions, InputObject, Object, Result, SimpleObject};
+use sqlx::{PgConnection, PgExecutor, Pool, Postgres};
+
+use crate::db::{BundleComponent, PrimaryKey};
+
+use super::extract_admin_claims;
+
+#[derive(InputObject)]
+struct BundleComponentInput {
+    product_id: PrimaryKey,
+    #[graphql(default = 1)]
+    quantity: i32,
+}
+
+#[derive(SimpleObject)]
+struct ProductConsumption {
+    product_id: PrimaryKey,
+    name: String,
+    count: i64,
+}
+
+#[derive(Default)]
+pub struct BundleQuery;
+
+#[Object]
+impl BundleQuery {
+    /// How many units of each product were bought, refunded purchases are not counted.
+    /// With `expand_bundles`, bundles are counted as the products they contain.
+    async fn product_consumption(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] expand_bundles: bool,
+    ) -> Result<Vec<ProductConsumption>> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+
+        let consumption = if expand_bundles {
+            sqlx::query_as!(
+                ProductConsumption,
+                r#"
+                SELECT products.id AS product_id, products.name,
+                SUM(consumed.quantity)::BIGINT AS "count!"
+                FROM (
+                    SELECT purchases.product_id, purchases.quantity FROM purchases
+                    WHERE NOT refunded AND NOT EXISTS (
+                        SELECT 1 FROM purchase_components WHERE purchase_id = purchases.id
+                    )
+                    UNION ALL
+                    SELECT purchase_components.product_id, purchase_components.quantity
+                    FROM purchase_components
+                    JOIN purchases ON purchases.id = purchase_components.purchase_id
+                    WHERE NOT purchases.refunded
+                ) AS consumed
+                JOIN products ON products.id = consumed.product_id
+                GROUP BY products.id, products.name
+                ORDER BY "count!" DESC
+                "#
+            )
+            .fetch_all(db)
+            .await
+        } else {
+            sqlx::query_as!(
+                ProductConsumption,
+                r#"
+                SELECT products.id AS product_id, products.name,
+                SUM(purchases.quantity)::BIGINT AS "count!"
+                FROM purchases
+                JOIN products ON products.id = purchases.product_id
+                WHERE NOT purchases.refunded
+                GROUP BY products.id, products.name
+                ORDER BY "count!" DESC
+                "#
+            )
+            .fetch_all(db)
+            .await
+        };
+
+        consumption.map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+}
+
+#[derive(Default)]
+pub struct BundleMutation;
+
+#[Object]
+impl BundleMutation {
+    /// Replaces the components of a bundle, an empty list turns it back into a normal product
+    async fn set_bundle_components(
+        &self,
+        ctx: &Context<'_>,
+        bundle_id: PrimaryKey,
+        components: Vec<BundleComponentInput>,
+    ) -> Result<Vec<BundleComponent>> {
+        extract_admin_claims(ctx)?;
+        let db: &Pool<Postgres> = ctx.data()?;
+
+        if components
+            .iter()
+            .any(|component| component.product_id == bundle_id)
+        {
+            return Err(async_graphql::Error::new("A bundle cannot contain itself"));
+        }
+
+        let component_ids: Vec<PrimaryKey> = components
+            .iter()
+            .map(|component| component.product_id)
+            .collect();
+        let nested = sqlx::query_scalar!(
+            "SELECT bundle_id FROM bundle_components WHERE bundle_id = ANY($1) LIMIT 1",
+            &component_ids
+        )
+        .fetch_optional(db)
+        .await?;
+        if let Some(nested) = nested {
+            return Err(async_graphql::Error::new(format!(
+                "Product {nested} is a bundle itself, bundles cannot be nested"
+            )));
+        }
+
+        let contained_in = sqlx::query_scalar!(
+            "SELECT bundle_id FROM bundle_components WHERE product_id = $1 LIMIT 1",
+            bundle_id
+        )
+        .fetch_optional(db)
+        .await?;
+        if let (Some(contained_in), false) = (contained_in, component_ids.is_empty()) {
+            return Err(async_graphql::Error::new(format!(
+                "Product {bundle_id} is part of bundle {contained_in}, bundles cannot be nested"
+            )));
+        }
+
+        let mut tx = db.begin().await?;
+
+        sqlx::query!(
+            "DELETE FROM bundle_components WHERE bundle_id = $1",
+            bundle_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let mut inserted = Vec::with_capacity(components.len());
+        for component in components {
+            let component = sqlx::query_as!(
+                BundleComponent,
+                r#"
+                INSERT INTO bundle_components (bundle_id, product_id, quantity)
+                VALUES ($1, $2, $3)
+                RETURNING *
+                "#,
+                bundle_id,
+                component.product_id,
+                component.quantity
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 400)))?;
+            inserted.push(component);
+        }
+
+        tx.commit().await?;
+
+        Ok(inserted)
+    }
+}
+
+pub async fn bundle_components(
+    db: impl PgExecutor<'_>,
+    bundle_id: PrimaryKey,
+) -> sqlx::Result<Vec<BundleComponent>> {
+    sqlx::query_as!(
+        BundleComponent,
+        "SELECT * FROM bundle_components WHERE bundle_id = $1 ORDER BY product_id",
+        bundle_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Takes the bought products out of stock. For bundles, the consumed components
+/// are recorded with the purchase and taken out of stock instead of the bundle.
+/// Only the bundle's own deposit type is charged, the deposits of its components
+/// are not, so a crate needs a deposit type covering its bottles.
+/// Returns the products whose stock changed, to publish once the purchase is committed.
+pub async fn consume_stock(
+    conn: &mut PgConnection,
+    purchase_id: PrimaryKey,
+    product_id: PrimaryKey,
+    quantity: i32,
+) -> sqlx::Result<Vec<PrimaryKey>> {
+    let components = bundle_components(&mut *conn, product_id).await?;
+
+    if components.is_empty() {
+        return adjust_stock(conn, &[(product_id, -quantity)]).await;
+    }
+
+    let mut consumed = Vec::new();
+    for component in components {
+        let component_quantity = component.quantity * quantity;
+        sqlx::query!(
+            r#"
+            INSERT INTO purchase_components (purchase_id, product_id, quantity)
+            VALUES ($1, $2, $3)
+            "#,
+            purchase_id,
+            component.product_id,
+            component_quantity
+        )
+        .execute(&mut *conn)
+        .await?;
+        consumed.push((component.product_id, -component_quantity));
+    }
+
+    adjust_stock(conn, &consumed).await
+}
+
+/// Puts the products consumed by a purchase back into stock.
+/// Returns the products whose stock changed, like `consume_stock`.
+pub async fn restore_stock(
+    conn: &mut PgConnection,
+    purchase_id: PrimaryKey,
+    product_id: PrimaryKey,
+    quantity: i32,
+) -> sqlx::Result<Vec<PrimaryKey>> {
+    let components = sqlx::query!(
+        "SELECT product_id, quantity FROM purchase_components WHERE purchase_id = $1",
+        purchase_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    if components.is_empty() {
+        return adjust_stock(conn, &[(product_id, quantity)]).await;
+    }
+
+    let restored: Vec<_> = components
+        .into_iter()
+        .map(|component| (component.product_id, component.quantity))
+        .collect();
+    adjust_stock(conn, &restored).await
+}
+
+/// Returns the products whose stock is tracked, the others are left alone
+async fn adjust_stock(
+    conn: &mut PgConnection,
+    deltas: &[(PrimaryKey, i32)],
+) -> sqlx::Result<Vec<PrimaryKey>> {
+    let mut changed = Vec::new();
+    for &(product_id, delta) in deltas {
+        let result = sqlx::query!(
+            "UPDATE products SET stock = stock + $2 WHERE id = $1 AND stock IS NOT NULL",
+            product_id,
+            delta
+        )
+        .execute(&mut *conn)
+        .await?;
+        if result.rows_affected() > 0 {
+            changed.push(product_id);
+        }
+    }
+    Ok(changed)
+}
@@ -0,0 +1,322 @@
+use async_graphql::{Context, ErrorExtensions, InputObject, Object, Result, SimpleObject};
+
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{BalanceChange, DepositReturn, DepositType, PrimaryKey},
+    events::{self, Event},
+};
+
+use super::{extract_admin_claims, extract_user_claims};
+
+#[derive(InputObject)]
+struct ReturnedEmpties {
+    deposit_type_id: PrimaryKey,
+    quantity: i32,
+}
+
+#[derive(SimpleObject)]
+struct DepositReport {
+    /// Money earned from sales, without deposits
+    revenue: i64,
+    deposits_charged: i64,
+    deposits_returned: i64,
+    /// Deposits charged but not yet returned, we owe this to our customers
+    deposit_liability: i64,
+    per_type: Vec<DepositTypeReport>,
+}
+
+#[derive(SimpleObject)]
+struct DepositTypeReport {
+    deposit_type_id: PrimaryKey,
+    name: String,
+    bottles_sold: i64,
+    bottles_returned: i64,
+    deposits_charged: i64,
+    deposits_returned: i64,
+    deposit_liability: i64,
+}
+
+#[derive(Default)]
+pub struct DepositQuery;
+
+#[Object]
+impl DepositQuery {
+    async fn deposit_types(&self, ctx: &Context<'_>) -> Result<Vec<DepositType>> {
+        let db = ctx.data()?;
+        sqlx::query_as!(DepositType, "SELECT * FROM deposit_types ORDER BY name")
+            .fetch_all(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn my_deposit_returns(&self, ctx: &Context<'_>) -> Result<Vec<DepositReturn>> {
+        let user_claims = extract_user_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            DepositReturn,
+            "SELECT * FROM deposit_returns WHERE account_id = $1 ORDER BY returned_at DESC",
+            user_claims.user_id
+        )
+        .fetch_all(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    /// Revenue and outstanding deposits, refunded purchases are not counted
+    async fn deposit_report(&self, ctx: &Context<'_>) -> Result<DepositReport> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+
+        let revenue = sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(paid_price), 0)::BIGINT AS "revenue!" FROM purchases WHERE NOT refunded"#
+        )
+        .fetch_one(db)
+        .await?;
+
+        let per_type = sqlx::query!(
+            r#"
+            SELECT id, name,
+            COALESCE((
+                SELECT SUM(quantity) FROM purchases
+                WHERE deposit_type_id = deposit_types.id AND NOT refunded
+            ), 0)::BIGINT AS "bottles_sold!",
+            COALESCE((
+                SELECT SUM(deposit) FROM purchases
+                WHERE deposit_type_id = deposit_types.id AND NOT refunded
+            ), 0)::BIGINT AS "deposits_charged!",
+            COALESCE((
+                SELECT SUM(quantity) FROM deposit_returns
+                WHERE deposit_type_id = deposit_types.id
+            ), 0)::BIGINT AS "bottles_returned!",
+            COALESCE((
+                SELECT SUM(amount) FROM deposit_returns
+                WHERE deposit_type_id = deposit_types.id
+            ), 0)::BIGINT AS "deposits_returned!"
+            FROM deposit_types
+            ORDER BY name
+            "#
+        )
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(|row| DepositTypeReport {
+            deposit_type_id: row.id,
+            name: row.name,
+            bottles_sold: row.bottles_sold,
+            bottles_returned: row.bottles_returned,
+            deposits_charged: row.deposits_charged,
+            deposits_returned: row.deposits_returned,
+            deposit_liability: row.deposits_charged - row.deposits_returned,
+        })
+        .collect::<Vec<_>>();
+
+        let deposits_charged = per_type.iter().map(|ty| ty.deposits_charged).sum();
+        let deposits_returned = per_type.iter().map(|ty| ty.deposits_returned).sum();
+
+        Ok(DepositReport {
+            revenue,
+            deposits_charged,
+            deposits_returned,
+            deposit_liability: deposits_charged - deposits_returned,
+            per_type,
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct DepositMutation;
+
+#[Object]
+impl DepositMutation {
+    /// the field id on the input object here is ignored and optional
+    async fn create_deposit_type(
+        &self,
+        ctx: &Context<'_>,
+        deposit_type: DepositType,
+    ) -> Result<DepositType> {
+        extract_admin_claims(ctx)?;
+        validate_deposit_type(&deposit_type)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            DepositType,
+            "INSERT INTO deposit_types (name, amount) VALUES ($1, $2) RETURNING *",
+            deposit_type.name,
+            deposit_type.amount
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn update_deposit_type(
+        &self,
+        ctx: &Context<'_>,
+        deposit_type: DepositType,
+    ) -> Result<DepositType> {
+        extract_admin_claims(ctx)?;
+        validate_deposit_type(&deposit_type)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            DepositType,
+            "UPDATE deposit_types SET name = $2, amount = $3 WHERE id = $1 RETURNING *",
+            deposit_type.id,
+            deposit_type.name,
+            deposit_type.amount
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    /// Only deposit types no purchase or return refers to can be deleted, products
+    /// charging it stop charging a deposit
+    async fn delete_deposit_type(&self, ctx: &Context<'_>, id: PrimaryKey) -> Result<bool> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+
+        let referenced = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM purchases WHERE deposit_type_id = $1)
+            OR EXISTS (SELECT 1 FROM deposit_returns WHERE deposit_type_id = $1) AS "referenced!"
+            "#,
+            id
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        if referenced {
+            return Err(async_graphql::Error::new(
+                "Deposit types purchases or returns refer to can't be deleted",
+            ));
+        }
+
+        sqlx::query!("DELETE FROM deposit_types WHERE id = $1", id)
+            .execute(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        Ok(true)
+    }
+
+    /// Credits the deposit of returned empty bottles to the logged in account.
+    /// An account can only return as many bottles of a deposit type as it paid
+    /// deposits for, and gets back the deposit it paid, even if the amount of the
+    /// deposit type changed since. Returns the total amount credited.
+    async fn return_empties(
+        &self,
+        ctx: &Context<'_>,
+        empties: Vec<ReturnedEmpties>,
+    ) -> Result<i64> {
+        let user_claims = extract_user_claims(ctx)?;
+        let db: &Pool<Postgres> = ctx.data()?;
+
+        if empties.iter().any(|empty| empty.quantity <= 0) {
+            return Err(async_graphql::Error::new("quantity needs to be positive"));
+        }
+
+        let mut tx = db.begin().await?;
+        let mut credited = 0;
+
+        // concurrent returns of the same account would both pass the check below
+        sqlx::query!(
+            "SELECT id FROM accounts WHERE id = $1 FOR UPDATE",
+            user_claims.user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for empty in empties {
+            // counted again for every entry, so returns already inserted above count too
+            let bottles = sqlx::query!(
+                r#"
+                SELECT
+                COALESCE((
+                    SELECT SUM(quantity) FROM purchases
+                    WHERE account_id = $1 AND deposit_type_id = $2 AND NOT refunded
+                ), 0)::BIGINT AS "bought!",
+                COALESCE((
+                    SELECT SUM(quantity) FROM deposit_returns
+                    WHERE account_id = $1 AND deposit_type_id = $2
+                ), 0)::BIGINT AS "returned!"
+                "#,
+                user_claims.user_id,
+                empty.deposit_type_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            let returnable = bottles.bought - bottles.returned;
+            if empty.quantity as i64 > returnable {
+                return Err(async_graphql::Error::new(format!(
+                    "Only {returnable} bottles of deposit type {} can be returned",
+                    empty.deposit_type_id
+                )));
+            }
+
+            // the deposit actually charged, bottles are returned oldest purchase first
+            let amount = sqlx::query_scalar!(
+                r#"
+                WITH bought AS (
+                    SELECT deposit / quantity AS unit_deposit, quantity,
+                    SUM(quantity) OVER (ORDER BY id) - quantity AS bought_before
+                    FROM purchases
+                    WHERE account_id = $1 AND deposit_type_id = $2 AND NOT refunded
+                )
+                SELECT COALESCE(SUM(unit_deposit * GREATEST(0,
+                    LEAST(bought_before + quantity, $3::BIGINT + $4::BIGINT) - GREATEST(bought_before, $3)
+                )), 0)::BIGINT AS "amount!"
+                FROM bought
+                "#,
+                user_claims.user_id,
+                empty.deposit_type_id,
+                bottles.returned,
+                empty.quantity as i64
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            let deposit_return = sqlx::query_as!(
+                DepositReturn,
+                r#"
+                INSERT INTO deposit_returns (account_id, deposit_type_id, quantity, amount)
+                VALUES ($1, $2, $3, $4)
+                RETURNING *
+                "#,
+                user_claims.user_id,
+                empty.deposit_type_id,
+                empty.quantity,
+                amount
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            credited += deposit_return.amount;
+        }
+
+        let balance = sqlx::query_scalar!(
+            "UPDATE accounts SET balance = balance + $2 WHERE id = $1 RETURNING balance",
+            user_claims.user_id,
+            credited
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        events::publish(Event::BalanceChanged(BalanceChange {
+            account_id: user_claims.user_id.clone(),
+            balance,
+        }));
+
+        Ok(credited)
+    }
+}
+
+fn validate_deposit_type(deposit_type: &DepositType) -> Result<()> {
+    if deposit_type.amount < 0 {
+        return Err(async_graphql::Error::new(
+            "Deposit amount cannot be negative",
+        ));
+    }
+    Ok(())
+}
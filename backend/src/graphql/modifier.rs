@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet};
+
+use async_graphql::{ComplexObject, Context, ErrorExtensions, Object, Result, SimpleObject};
+use sqlx::{Pool, Postgres};
+
+use crate::db::{ModifierGroup, ModifierOption, PrimaryKey};
+
+use super::extract_admin_claims;
+
+#[ComplexObject]
+impl ModifierGroup {
+    async fn options(&self, ctx: &Context<'_>) -> Result<Vec<ModifierOption>> {
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            ModifierOption,
+            "SELECT * FROM modifier_options WHERE group_id = $1 ORDER BY id",
+            self.id
+        )
+        .fetch_all(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+}
+
+#[derive(SimpleObject)]
+struct ModifierOptionCount {
+    modifier_option_id: PrimaryKey,
+    name: String,
+    product_id: PrimaryKey,
+    count: i64,
+}
+
+#[derive(Default)]
+pub struct ModifierQuery;
+
+#[Object]
+impl ModifierQuery {
+    /// How often each modifier option was chosen, refunded purchases are not counted
+    async fn modifier_option_counts(
+        &self,
+        ctx: &Context<'_>,
+        product_id: Option<PrimaryKey>,
+    ) -> Result<Vec<ModifierOptionCount>> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            ModifierOptionCount,
+            r#"
+            SELECT modifier_options.id AS modifier_option_id, modifier_options.name,
+            modifier_groups.product_id,
+            COALESCE(SUM(purchases.quantity), 0)::BIGINT AS "count!"
+            FROM modifier_options
+            JOIN modifier_groups ON modifier_groups.id = modifier_options.group_id
+            LEFT JOIN purchase_modifiers
+                ON purchase_modifiers.modifier_option_id = modifier_options.id
+            LEFT JOIN purchases
+                ON purchases.id = purchase_modifiers.purchase_id AND NOT purchases.refunded
+            WHERE $1::BIGINT IS NULL OR modifier_groups.product_id = $1
+            GROUP BY modifier_options.id, modifier_options.name, modifier_groups.product_id
+            ORDER BY "count!" DESC
+            "#,
+            product_id
+        )
+        .fetch_all(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+}
+
+#[derive(Default)]
+pub struct ModifierMutation;
+
+#[Object]
+impl ModifierMutation {
+    /// the field id on the input object here is ignored and optional
+    async fn create_modifier_group(
+        &self,
+        ctx: &Context<'_>,
+        group: ModifierGroup,
+    ) -> Result<ModifierGroup> {
+        extract_admin_claims(ctx)?;
+        validate_modifier_group(&group)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            ModifierGroup,
+            r#"
+            INSERT INTO modifier_groups (product_id, name, min_selected, max_selected)
+            VALUES ($1, $2, $3, $4)
+            RETURNING *
+            "#,
+            group.product_id,
+            group.name,
+            group.min_selected,
+            group.max_selected
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    /// The product a group belongs to can't be changed
+    async fn update_modifier_group(
+        &self,
+        ctx: &Context<'_>,
+        group: ModifierGroup,
+    ) -> Result<ModifierGroup> {
+        extract_admin_claims(ctx)?;
+        validate_modifier_group(&group)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            ModifierGroup,
+            r#"
+            UPDATE modifier_groups
+            SET name = $2, min_selected = $3, max_selected = $4
+            WHERE id = $1
+            RETURNING *
+            "#,
+            group.id,
+            group.name,
+            group.min_selected,
+            group.max_selected
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn delete_modifier_group(&self, ctx: &Context<'_>, id: PrimaryKey) -> Result<bool> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query!("DELETE FROM modifier_groups WHERE id = $1", id)
+            .execute(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        Ok(true)
+    }
+
+    /// the field id on the input object here is ignored and optional
+    async fn create_modifier_option(
+        &self,
+        ctx: &Context<'_>,
+        option: ModifierOption,
+    ) -> Result<ModifierOption> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            ModifierOption,
+            r#"
+            INSERT INTO modifier_options (group_id, name, price_delta)
+            VALUES ($1, $2, $3)
+            RETURNING *
+            "#,
+            option.group_id,
+            option.name,
+            option.price_delta
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn update_modifier_option(
+        &self,
+        ctx: &Context<'_>,
+        option: ModifierOption,
+    ) -> Result<ModifierOption> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            ModifierOption,
+            r#"
+            UPDATE modifier_options
+            SET name = $2, price_delta = $3
+            WHERE id = $1
+            RETURNING *
+            "#,
+            option.id,
+            option.name,
+            option.price_delta
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn delete_modifier_option(&self, ctx: &Context<'_>, id: PrimaryKey) -> Result<bool> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query!("DELETE FROM modifier_options WHERE id = $1", id)
+            .execute(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        Ok(true)
+    }
+}
+
+fn validate_modifier_group(group: &ModifierGroup) -> Result<()> {
+    if group.min_selected < 0 || group.min_selected > group.max_selected {
+        return Err(async_graphql::Error::new(
+            "min_selected needs to be between 0 and max_selected",
+        ));
+    }
+    Ok(())
+}
+
+pub async fn modifier_groups(
+    db: &Pool<Postgres>,
+    product_id: PrimaryKey,
+) -> sqlx::Result<Vec<ModifierGroup>> {
+    sqlx::query_as!(
+        ModifierGroup,
+        "SELECT * FROM modifier_groups WHERE product_id = $1 ORDER BY id",
+        product_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Loads the selected options and checks them against the modifier groups of the product
+pub async fn selected_modifiers(
+    db: &Pool<Postgres>,
+    product_id: PrimaryKey,
+    option_ids: &[PrimaryKey],
+) -> Result<Vec<ModifierOption>> {
+    // ANY($1) below would quietly treat them as one
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = option_ids.iter().find(|id| !seen.insert(**id)) {
+        return Err(async_graphql::Error::new(format!(
+            "Modifier option {duplicate} is selected more than once"
+        )));
+    }
+
+    let options = sqlx::query_as!(
+        ModifierOption,
+        r#"
+        SELECT modifier_options.* FROM modifier_options
+        JOIN modifier_groups ON modifier_groups.id = modifier_options.group_id
+        WHERE modifier_options.id = ANY($1) AND modifier_groups.product_id = $2
+        "#,
+        option_ids,
+        product_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    if let Some(unknown) = option_ids
+        .iter()
+        .find(|id| !options.iter().any(|option| option.id == **id))
+    {
+        return Err(async_graphql::Error::new(format!(
+            "Modifier option {unknown} doesn't exist for product {product_id}"
+        )));
+    }
+
+    let mut selected_per_group: HashMap<PrimaryKey, i32> = HashMap::new();
+    for option in &options {
+        *selected_per_group.entry(option.group_id).or_default() += 1;
+    }
+
+    for group in modifier_groups(db, product_id).await? {
+        let selected = selected_per_group.get(&group.id).copied().unwrap_or(0);
+        if selected < group.min_selected || selected > group.max_selected {
+            return Err(async_graphql::Error::new(format!(
+                "Select between {} and {} options for {}",
+                group.min_selected, group.max_selected, group.name
+            )));
+        }
+    }
+
+    Ok(options)
+}
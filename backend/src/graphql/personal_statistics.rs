@@ -0,0 +1,213 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use async_graphql::{Context, Object, Result, SimpleObject};
+use chrono::{Datelike, Days, NaiveDate, Timelike, Utc};
+
+use crate::db::{PrimaryKey, ProductType};
+
+use super::{extract_user_claims, statistics::parse_timezone, types::period::Period};
+
+#[derive(SimpleObject)]
+struct PersonalStatistics {
+    /// Timezone used for days, months and hours
+    timezone: String,
+    total_units: i64,
+    /// Paid prices, without deposits
+    total_spent: i64,
+    /// Sorted by units, most bought first
+    per_product: Vec<ProductTotal>,
+    per_product_type: Vec<ProductTypeTotal>,
+    /// Only months with purchases, oldest first
+    per_month: Vec<MonthTotal>,
+    /// Consecutive days with purchases up to today, or up to yesterday
+    /// if nothing was bought today yet
+    current_streak_days: i32,
+    longest_streak_days: i32,
+    /// Units bought per hour of the day, always 24 entries
+    per_hour: Vec<HourTotal>,
+    /// The hour of the day with the most units bought
+    busiest_hour: Option<u32>,
+}
+
+#[derive(SimpleObject)]
+struct ProductTotal {
+    product_id: PrimaryKey,
+    name: String,
+    product_type: ProductType,
+    units: i64,
+    spent: i64,
+}
+
+#[derive(SimpleObject)]
+struct ProductTypeTotal {
+    product_type: ProductType,
+    units: i64,
+    spent: i64,
+}
+
+#[derive(SimpleObject)]
+struct MonthTotal {
+    /// First day of the month
+    month: NaiveDate,
+    units: i64,
+    spent: i64,
+}
+
+#[derive(SimpleObject)]
+struct HourTotal {
+    hour: u32,
+    units: i64,
+}
+
+#[derive(Default)]
+pub struct PersonalStatisticsQuery;
+
+#[Object]
+impl PersonalStatisticsQuery {
+    /// Statistics over the non-refunded purchases of the logged in account,
+    /// over all time if no period is given
+    async fn my_statistics(
+        &self,
+        ctx: &Context<'_>,
+        period: Option<Period>,
+        #[graphql(desc = "IANA timezone, e.g. Europe/Berlin. Defaults to the configured one")]
+        timezone: Option<String>,
+    ) -> Result<PersonalStatistics> {
+        let user_claims = extract_user_claims(ctx)?;
+        let db = ctx.data()?;
+        let (timezone, tz) = parse_timezone(timezone)?;
+
+        let now = Utc::now().with_timezone(&tz).naive_local();
+        let (from, to) = match period {
+            Some(period) => {
+                let (from, to) = period.range_until(now)?;
+                (Some(from), Some(to))
+            }
+            None => (None, None),
+        };
+
+        // purchases store the wall clock time of the database, which is converted to `tz` here
+        let purchases = sqlx::query!(
+            r#"
+            SELECT purchases.created_at::timestamptz AT TIME ZONE $2 AS "created_at!",
+            purchases.quantity, purchases.paid_price,
+            products.id, products.name, products.product_type AS "product_type: ProductType"
+            FROM purchases
+            JOIN products ON products.id = purchases.product_id
+            WHERE purchases.account_id = $1 AND NOT purchases.refunded
+            AND purchases.created_at IS NOT NULL
+            AND ($3::TIMESTAMP IS NULL OR purchases.created_at::timestamptz AT TIME ZONE $2 >= $3)
+            AND ($4::TIMESTAMP IS NULL OR purchases.created_at::timestamptz AT TIME ZONE $2 < $4)
+            ORDER BY purchases.created_at
+            "#,
+            user_claims.user_id,
+            timezone,
+            from,
+            to
+        )
+        .fetch_all(db)
+        .await?;
+
+        let mut per_product: BTreeMap<PrimaryKey, ProductTotal> = BTreeMap::new();
+        let mut per_month: BTreeMap<NaiveDate, MonthTotal> = BTreeMap::new();
+        let mut per_hour: Vec<HourTotal> =
+            (0..24).map(|hour| HourTotal { hour, units: 0 }).collect();
+        let mut days = BTreeSet::new();
+
+        for purchase in &purchases {
+            let units = purchase.quantity as i64;
+
+            let product = per_product
+                .entry(purchase.id)
+                .or_insert_with(|| ProductTotal {
+                    product_id: purchase.id,
+                    name: purchase.name.clone(),
+                    product_type: purchase.product_type,
+                    units: 0,
+                    spent: 0,
+                });
+            product.units += units;
+            product.spent += purchase.paid_price;
+
+            let date = purchase.created_at.date();
+            let month = date.with_day(1).unwrap();
+            let month_total = per_month.entry(month).or_insert_with(|| MonthTotal {
+                month,
+                units: 0,
+                spent: 0,
+            });
+            month_total.units += units;
+            month_total.spent += purchase.paid_price;
+
+            per_hour[purchase.created_at.hour() as usize].units += units;
+            days.insert(date);
+        }
+
+        let mut per_product: Vec<ProductTotal> = per_product.into_values().collect();
+        per_product.sort_by(|a, b| b.units.cmp(&a.units).then_with(|| a.name.cmp(&b.name)));
+
+        let per_product_type = [ProductType::HotDrink, ProductType::ColdDrink]
+            .into_iter()
+            .map(|product_type| {
+                let of_type = per_product
+                    .iter()
+                    .filter(|product| product.product_type == product_type);
+                ProductTypeTotal {
+                    product_type,
+                    units: of_type.clone().map(|product| product.units).sum(),
+                    spent: of_type.map(|product| product.spent).sum(),
+                }
+            })
+            .collect();
+
+        let busiest_hour = per_hour
+            .iter()
+            .filter(|hour| hour.units > 0)
+            .max_by_key(|hour| hour.units)
+            .map(|hour| hour.hour);
+
+        Ok(PersonalStatistics {
+            timezone,
+            total_units: per_product.iter().map(|product| product.units).sum(),
+            total_spent: per_product.iter().map(|product| product.spent).sum(),
+            per_product,
+            per_product_type,
+            per_month: per_month.into_values().collect(),
+            current_streak_days: current_streak(&days, now.date()),
+            longest_streak_days: longest_streak(&days),
+            per_hour,
+            busiest_hour,
+        })
+    }
+}
+
+fn current_streak(days: &BTreeSet<NaiveDate>, today: NaiveDate) -> i32 {
+    let mut day = if days.contains(&today) {
+        today
+    } else {
+        today - Days::new(1)
+    };
+
+    let mut streak = 0;
+    while days.contains(&day) {
+        streak += 1;
+        day = day - Days::new(1);
+    }
+    streak
+}
+
+fn longest_streak(days: &BTreeSet<NaiveDate>) -> i32 {
+    let mut longest = 0;
+    let mut streak = 0;
+    let mut previous: Option<NaiveDate> = None;
+
+    for day in days {
+        streak = match previous {
+            Some(previous) if previous + Days::new(1) == *day => streak + 1,
+            _ => 1,
+        };
+        longest = longest.max(streak);
+        previous = Some(*day);
+    }
+    longest
+}
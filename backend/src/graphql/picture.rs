@@ -0,0 +1,26 @@
+use async_graphql::{ComplexObject, ErrorExtensions, Result};
+
+use crate::{
+    db::{Picture, PictureSize},
+    picture,
+};
+
+#[ComplexObject]
+impl Picture {
+    /// Where the picture can be fetched from in its full size
+    async fn url(&self) -> Result<String> {
+        picture::url(&self.full_key)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+}
+
+#[ComplexObject]
+impl PictureSize {
+    /// Where the picture can be fetched from in this size
+    async fn url(&self) -> Result<String> {
+        picture::url(&self.full_key)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+}
@@ -0,0 +1,241 @@
+use async_graphql::{Context, ErrorExtensions, Object, Result};
+use sqlx::{Pool, Postgres};
+
+use crate::db::{AccountGroup, PriceList, PriceListEntry, PrimaryKey, ProductType};
+
+use super::extract_admin_claims;
+
+#[derive(Default)]
+pub struct PriceListQuery;
+
+#[Object]
+impl PriceListQuery {
+    async fn price_lists(&self, ctx: &Context<'_>) -> Result<Vec<PriceList>> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(PriceList, "SELECT * FROM price_lists ORDER BY name")
+            .fetch_all(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn price_list_entries(
+        &self,
+        ctx: &Context<'_>,
+        price_list_id: PrimaryKey,
+    ) -> Result<Vec<PriceListEntry>> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            PriceListEntry,
+            "SELECT * FROM price_list_entries WHERE price_list_id = $1 ORDER BY product_id",
+            price_list_id
+        )
+        .fetch_all(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn account_groups(&self, ctx: &Context<'_>) -> Result<Vec<AccountGroup>> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(AccountGroup, "SELECT * FROM account_groups ORDER BY name")
+            .fetch_all(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+}
+
+#[derive(Default)]
+pub struct PriceListMutation;
+
+#[Object]
+impl PriceListMutation {
+    async fn create_price_list(&self, ctx: &Context<'_>, name: String) -> Result<PriceList> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            PriceList,
+            "INSERT INTO price_lists (name) VALUES ($1) RETURNING *",
+            name
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn delete_price_list(&self, ctx: &Context<'_>, id: PrimaryKey) -> Result<bool> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query!("DELETE FROM price_lists WHERE id = $1", id)
+            .execute(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        Ok(true)
+    }
+
+    /// Overrides the price of a product in a price list
+    async fn set_price_list_entry(
+        &self,
+        ctx: &Context<'_>,
+        price_list_id: PrimaryKey,
+        product_id: PrimaryKey,
+        price: i64,
+    ) -> Result<PriceListEntry> {
+        extract_admin_claims(ctx)?;
+        if price < 0 {
+            return Err(async_graphql::Error::new("Price cannot be negative"));
+        }
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            PriceListEntry,
+            r#"
+            INSERT INTO price_list_entries (price_list_id, product_id, price)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (price_list_id, product_id) DO UPDATE SET price = $3
+            RETURNING *
+            "#,
+            price_list_id,
+            product_id,
+            price
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    /// Removes a price override, the product falls back to its base price
+    async fn remove_price_list_entry(
+        &self,
+        ctx: &Context<'_>,
+        price_list_id: PrimaryKey,
+        product_id: PrimaryKey,
+    ) -> Result<bool> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query!(
+            "DELETE FROM price_list_entries WHERE price_list_id = $1 AND product_id = $2",
+            price_list_id,
+            product_id
+        )
+        .execute(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        Ok(true)
+    }
+
+    /// the field id on the input object here is ignored and optional
+    async fn create_account_group(
+        &self,
+        ctx: &Context<'_>,
+        group: AccountGroup,
+    ) -> Result<AccountGroup> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            AccountGroup,
+            "INSERT INTO account_groups (name, price_list_id) VALUES ($1, $2) RETURNING *",
+            group.name,
+            group.price_list_id
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn update_account_group(
+        &self,
+        ctx: &Context<'_>,
+        group: AccountGroup,
+    ) -> Result<AccountGroup> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            AccountGroup,
+            r#"
+            UPDATE account_groups
+            SET name = $2, price_list_id = $3
+            WHERE id = $1
+            RETURNING *
+            "#,
+            group.id,
+            group.name,
+            group.price_list_id
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn delete_account_group(&self, ctx: &Context<'_>, id: PrimaryKey) -> Result<bool> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query!("DELETE FROM account_groups WHERE id = $1", id)
+            .execute(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        Ok(true)
+    }
+
+    /// Moves an account into a group, or out of any group if `group_id` is null
+    async fn set_account_group(
+        &self,
+        ctx: &Context<'_>,
+        account_id: String,
+        group_id: Option<PrimaryKey>,
+    ) -> Result<bool> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query!(
+            "UPDATE accounts SET group_id = $2 WHERE id = $1",
+            account_id,
+            group_id
+        )
+        .execute(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        Ok(true)
+    }
+}
+
+/// The price an account pays for one unit of a product
+pub struct AccountPrice {
+    pub price: i64,
+    pub product_type: ProductType,
+    /// `None` if there's no override for this account and the base price applies
+    pub price_list_id: Option<PrimaryKey>,
+    pub deposit_type_id: Option<PrimaryKey>,
+    /// Deposit per unit, charged on top of `price`
+    pub deposit: i64,
+}
+
+pub async fn account_price(
+    db: &Pool<Postgres>,
+    account_id: &str,
+    product_id: PrimaryKey,
+) -> sqlx::Result<AccountPrice> {
+    let row = sqlx::query!(
+        r#"
+        SELECT account_price($1, products.id) AS "price!",
+        products.product_type AS "product_type: ProductType",
+        entry.price_list_id AS "price_list_id?",
+        products.deposit_type_id, deposit_types.amount AS "deposit?"
+        FROM products
+        LEFT JOIN LATERAL account_price_list_entry($1, products.id) AS entry ON true
+        LEFT JOIN deposit_types ON deposit_types.id = products.deposit_type_id
+        WHERE products.id = $2
+        "#,
+        account_id,
+        product_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(AccountPrice {
+        price: row.price,
+        product_type: row.product_type,
+        price_list_id: row.price_list_id,
+        deposit_type_id: row.deposit_type_id,
+        deposit: row.deposit.unwrap_or(0),
+    })
+}
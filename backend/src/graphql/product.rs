@@ -1,8 +1,83 @@
-use async_graphql::{Context, ErrorExtensions, Object, Result};
+use async_graphql::{ComplexObject, Context, ErrorExtensions, Object, Result, Subscription};
+use futures_util::{Stream, StreamExt};
+use sqlx::{Pool, Postgres};
 
-use crate::db::{PrimaryKey, Product, ProductType, ProductWithFavorite};
+use crate::{
+    db::{
+        BundleComponent, ModifierGroup, Picture, PrimaryKey, Product, ProductImage, ProductType,
+        ProductUpdate, ProductWithFavorite,
+    },
+    events::{self, Event},
+    picture::full_product_picture_key,
+};
 
-use super::extract_user_claims;
+use super::{bundle, extract_admin_claims, extract_user_claims, modifier, until_expired};
+
+#[ComplexObject]
+impl Product {
+    async fn picture(&self) -> Option<Picture> {
+        self.picture
+            .clone()
+            .map(|key| Picture::new(key, full_product_picture_key))
+    }
+
+    /// The whole gallery in order, `picture` is the first image
+    async fn images(&self, ctx: &Context<'_>) -> Result<Vec<ProductImage>> {
+        let db = ctx.data()?;
+        Ok(product_images(db, self.id).await?)
+    }
+
+    async fn modifier_groups(&self, ctx: &Context<'_>) -> Result<Vec<ModifierGroup>> {
+        let db = ctx.data()?;
+        Ok(modifier::modifier_groups(db, self.id).await?)
+    }
+
+    /// The products contained in this product, empty if it isn't a bundle
+    async fn bundle_components(&self, ctx: &Context<'_>) -> Result<Vec<BundleComponent>> {
+        let db = ctx.data()?;
+        Ok(bundle::bundle_components(db, self.id).await?)
+    }
+}
+
+#[ComplexObject]
+impl ProductWithFavorite {
+    async fn picture(&self) -> Option<Picture> {
+        self.picture
+            .clone()
+            .map(|key| Picture::new(key, full_product_picture_key))
+    }
+
+    /// The whole gallery in order, `picture` is the first image
+    async fn images(&self, ctx: &Context<'_>) -> Result<Vec<ProductImage>> {
+        let db = ctx.data()?;
+        Ok(product_images(db, self.id).await?)
+    }
+
+    async fn modifier_groups(&self, ctx: &Context<'_>) -> Result<Vec<ModifierGroup>> {
+        let db = ctx.data()?;
+        Ok(modifier::modifier_groups(db, self.id).await?)
+    }
+}
+
+#[ComplexObject]
+impl ProductImage {
+    async fn picture(&self) -> Picture {
+        Picture::new(self.picture.clone(), full_product_picture_key)
+    }
+}
+
+pub async fn product_images(
+    db: &Pool<Postgres>,
+    product_id: PrimaryKey,
+) -> sqlx::Result<Vec<ProductImage>> {
+    sqlx::query_as!(
+        ProductImage,
+        "SELECT * FROM product_images WHERE product_id = $1 ORDER BY position",
+        product_id
+    )
+    .fetch_all(db)
+    .await
+}
 
 #[derive(Default)]
 pub struct ProductQuery;
@@ -14,7 +89,8 @@ impl ProductQuery {
         sqlx::query_as!(
             Product,
             r#"
-        SELECT id, name, price, picture, product_type as "product_type: ProductType"
+        SELECT id, name, price, picture, product_type as "product_type: ProductType", deposit_type_id,
+        stock
         FROM products
             "#
         )
@@ -28,7 +104,8 @@ impl ProductQuery {
         sqlx::query_as!(
             Product,
             r#"
-        SELECT id, name, price, picture, product_type as "product_type: ProductType"
+        SELECT id, name, price, picture, product_type as "product_type: ProductType", deposit_type_id,
+        stock
         FROM products
         WHERE id = $1
             "#,
@@ -39,6 +116,7 @@ impl ProductQuery {
         .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
     }
 
+    /// `price` is the price the logged in account pays, taking its price list into account
     async fn products_with_favorites(
         &self,
         ctx: &Context<'_>,
@@ -50,7 +128,11 @@ impl ProductQuery {
         let products = sqlx::query_as!(
             ProductWithFavorite,
             r#"
-            SELECT id, name, price, picture, product_type as "product_type: ProductType",
+            SELECT id, name, picture, product_type as "product_type: ProductType",
+            COALESCE((
+                SELECT amount FROM deposit_types WHERE id = products.deposit_type_id
+            ), 0) as "deposit!",
+            account_price($1, id) as "price!",
             (
                 SELECT 1 FROM favorites WHERE account_id=$1 AND product_id=products.id
             ) IS NOT NULL as "is_favorite!"
@@ -80,7 +162,11 @@ impl ProductQuery {
         let product = sqlx::query_as!(
             ProductWithFavorite,
             r#"
-            SELECT id, name, price, picture, product_type as "product_type: ProductType",
+            SELECT id, name, picture, product_type as "product_type: ProductType",
+            COALESCE((
+                SELECT amount FROM deposit_types WHERE id = products.deposit_type_id
+            ), 0) as "deposit!",
+            account_price($1, id) as "price!",
             (
                 SELECT 1 FROM favorites WHERE account_id=$1 AND product_id=products.id
             ) IS NOT NULL as "is_favorite!"
@@ -105,48 +191,104 @@ impl ProductMutation {
     /// the field id on the input object here is ignored and optional
     async fn create_product(&self, ctx: &Context<'_>, product: Product) -> Result<Product> {
         let db = ctx.data()?;
-        sqlx::query_as!(
+        let product = sqlx::query_as!(
             Product,
             r#"
-        INSERT INTO products ( name, product_type, price )
-        VALUES ( $1, $2, $3 )
-        RETURNING id, name, price, picture, product_type AS "product_type!: ProductType"
+        INSERT INTO products ( name, product_type, price, deposit_type_id, stock )
+        VALUES ( $1, $2, $3, $4, $5 )
+        RETURNING id, name, price, picture, product_type AS "product_type!: ProductType",
+            deposit_type_id, stock
             "#,
             product.name,
             product.product_type as ProductType,
-            product.price
+            product.price,
+            product.deposit_type_id,
+            product.stock
         )
         .fetch_one(db)
         .await
-        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+
+        events::publish(Event::ProductUpdated(ProductUpdate {
+            id: product.id,
+            product: Some(product.clone()),
+        }));
+        Ok(product)
     }
 
+    /// `stock` is ignored here, purchases change it concurrently, see `adjust_stock`
     async fn update_product(&self, ctx: &Context<'_>, product: Product) -> Result<Product> {
         let db = ctx.data()?;
-        sqlx::query_as!(
+        let product = sqlx::query_as!(
             Product,
             r#"
             UPDATE products
-            SET name = $2, product_type = $3, price = $4
+            SET name = $2, product_type = $3, price = $4, deposit_type_id = $5
             WHERE id = $1
-            RETURNING id, name, price, picture, product_type AS "product_type!: ProductType"
+            RETURNING id, name, price, picture, product_type AS "product_type!: ProductType",
+            deposit_type_id, stock
             "#,
             product.id,
             product.name,
             product.product_type as ProductType,
-            product.price
+            product.price,
+            product.deposit_type_id
         )
         .fetch_one(db)
         .await
-        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+
+        events::publish(Event::ProductUpdated(ProductUpdate {
+            id: product.id,
+            product: Some(product.clone()),
+        }));
+        Ok(product)
+    }
+
+    /// Adds `delta` units to the stock of a product, negative deltas take units away.
+    /// Products whose stock isn't tracked yet start being tracked from 0.
+    async fn adjust_stock(
+        &self,
+        ctx: &Context<'_>,
+        product_id: PrimaryKey,
+        delta: i32,
+    ) -> Result<Product> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        let product = sqlx::query_as!(
+            Product,
+            r#"
+            UPDATE products
+            SET stock = COALESCE(stock, 0) + $2
+            WHERE id = $1
+            RETURNING id, name, price, picture, product_type AS "product_type!: ProductType",
+            deposit_type_id, stock
+            "#,
+            product_id,
+            delta
+        )
+        .fetch_optional(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?
+        .ok_or_else(|| async_graphql::Error::new("Unknown product"))?;
+
+        events::publish(Event::ProductUpdated(ProductUpdate {
+            id: product.id,
+            product: Some(product.clone()),
+        }));
+        Ok(product)
     }
 
     async fn delete_product(&self, ctx: &Context<'_>, id: PrimaryKey) -> Result<bool> {
         let db = ctx.data()?;
-        sqlx::query!(r"DELETE FROM products WHERE id = $1", id)
+        let deleted = sqlx::query!(r"DELETE FROM products WHERE id = $1", id)
             .execute(db)
             .await
-            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?
+            .rows_affected();
+        if deleted > 0 {
+            events::publish(Event::ProductUpdated(ProductUpdate { id, product: None }));
+        }
         Ok(true)
     }
 
@@ -196,3 +338,27 @@ impl ProductMutation {
         Ok(!is_favorite_before)
     }
 }
+
+#[derive(Default)]
+pub struct ProductSubscription;
+
+#[Subscription]
+impl ProductSubscription {
+    /// Fires for every change to a product, including its stock and pictures,
+    /// for logged in users
+    async fn product_updated(
+        &self,
+        ctx: &Context<'_>,
+    ) -> Result<impl Stream<Item = ProductUpdate>> {
+        let user_claims = extract_user_claims(ctx)?;
+        Ok(until_expired(
+            events::subscribe().filter_map(|event| async move {
+                match event {
+                    Event::ProductUpdated(update) => Some(update),
+                    _ => None,
+                }
+            }),
+            user_claims,
+        ))
+    }
+}
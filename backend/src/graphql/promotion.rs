@@ -0,0 +1,210 @@
+use async_graphql::{Context, ErrorExtensions, Object, Result};
+use chrono::Local;
+use sqlx::{Pool, Postgres};
+
+use crate::db::{DiscountType, PrimaryKey, ProductType, Promotion};
+
+use super::extract_admin_claims;
+
+#[derive(Default)]
+pub struct PromotionQuery;
+
+#[Object]
+impl PromotionQuery {
+    async fn promotions(&self, ctx: &Context<'_>) -> Result<Vec<Promotion>> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        all_promotions(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    /// Promotions which are running right now, optionally only the ones
+    /// which can apply to products of the given type
+    async fn active_promotions(
+        &self,
+        ctx: &Context<'_>,
+        product_type: Option<ProductType>,
+    ) -> Result<Vec<Promotion>> {
+        let db = ctx.data()?;
+        let now = Local::now().naive_local();
+
+        let promotions = sqlx::query_as!(
+            Promotion,
+            r#"
+            SELECT id, name, discount_type AS "discount_type: DiscountType", discount_value,
+            product_id, product_type AS "product_type: ProductType", weekdays,
+            start_time, end_time, valid_from, valid_until
+            FROM promotions
+            WHERE promotion_active_at(promotions, $1)
+            AND (product_type IS NULL OR $2::product_type IS NULL OR product_type = $2)
+            ORDER BY id
+            "#,
+            now,
+            product_type as Option<ProductType>
+        )
+        .fetch_all(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+
+        Ok(promotions)
+    }
+}
+
+#[derive(Default)]
+pub struct PromotionMutation;
+
+#[Object]
+impl PromotionMutation {
+    /// the field id on the input object here is ignored and optional
+    async fn create_promotion(&self, ctx: &Context<'_>, promotion: Promotion) -> Result<Promotion> {
+        extract_admin_claims(ctx)?;
+        validate_promotion(&promotion)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            Promotion,
+            r#"
+            INSERT INTO promotions
+            (name, discount_type, discount_value, product_id, product_type,
+             weekdays, start_time, end_time, valid_from, valid_until)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            RETURNING id, name, discount_type AS "discount_type!: DiscountType", discount_value,
+            product_id, product_type AS "product_type: ProductType", weekdays,
+            start_time, end_time, valid_from, valid_until
+            "#,
+            promotion.name,
+            promotion.discount_type as DiscountType,
+            promotion.discount_value,
+            promotion.product_id,
+            promotion.product_type as Option<ProductType>,
+            &promotion.weekdays,
+            promotion.start_time,
+            promotion.end_time,
+            promotion.valid_from,
+            promotion.valid_until
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn update_promotion(&self, ctx: &Context<'_>, promotion: Promotion) -> Result<Promotion> {
+        extract_admin_claims(ctx)?;
+        validate_promotion(&promotion)?;
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            Promotion,
+            r#"
+            UPDATE promotions
+            SET name = $2, discount_type = $3, discount_value = $4, product_id = $5,
+            product_type = $6, weekdays = $7, start_time = $8, end_time = $9,
+            valid_from = $10, valid_until = $11
+            WHERE id = $1
+            RETURNING id, name, discount_type AS "discount_type!: DiscountType", discount_value,
+            product_id, product_type AS "product_type: ProductType", weekdays,
+            start_time, end_time, valid_from, valid_until
+            "#,
+            promotion.id,
+            promotion.name,
+            promotion.discount_type as DiscountType,
+            promotion.discount_value,
+            promotion.product_id,
+            promotion.product_type as Option<ProductType>,
+            &promotion.weekdays,
+            promotion.start_time,
+            promotion.end_time,
+            promotion.valid_from,
+            promotion.valid_until
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+
+    async fn delete_promotion(&self, ctx: &Context<'_>, id: PrimaryKey) -> Result<bool> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        sqlx::query!("DELETE FROM promotions WHERE id = $1", id)
+            .execute(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        Ok(true)
+    }
+}
+
+fn validate_promotion(promotion: &Promotion) -> Result<()> {
+    if promotion.discount_value < 0 {
+        return Err(async_graphql::Error::new("Discount cannot be negative"));
+    }
+    if promotion.discount_type == DiscountType::Percentage && promotion.discount_value > 100 {
+        return Err(async_graphql::Error::new(
+            "Percentage discount needs to be between 0 and 100",
+        ));
+    }
+    if let Some(weekday) = promotion
+        .weekdays
+        .iter()
+        .find(|day| !(1..=7).contains(*day))
+    {
+        return Err(async_graphql::Error::new(format!(
+            "Invalid weekday {weekday}, weekdays go from 1 (monday) to 7 (sunday)"
+        )));
+    }
+    if let (Some(valid_from), Some(valid_until)) = (promotion.valid_from, promotion.valid_until) {
+        if valid_from >= valid_until {
+            return Err(async_graphql::Error::new(
+                "Promotions need to start before they end",
+            ));
+        }
+    }
+    Ok(())
+}
+
+async fn all_promotions(db: &Pool<Postgres>) -> sqlx::Result<Vec<Promotion>> {
+    sqlx::query_as!(
+        Promotion,
+        r#"
+        SELECT id, name, discount_type AS "discount_type: DiscountType", discount_value,
+        product_id, product_type AS "product_type: ProductType", weekdays,
+        start_time, end_time, valid_from, valid_until
+        FROM promotions
+        ORDER BY id
+        "#
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Finds the currently active promotion granting the highest discount
+/// on one unit of the given product
+pub async fn best_promotion(
+    db: &Pool<Postgres>,
+    product_id: PrimaryKey,
+    product_type: ProductType,
+    unit_price: i64,
+) -> sqlx::Result<Option<Promotion>> {
+    let now = Local::now().naive_local();
+
+    let best = sqlx::query_as!(
+        Promotion,
+        r#"
+        SELECT id, name, discount_type AS "discount_type: DiscountType", discount_value,
+        product_id, product_type AS "product_type: ProductType", weekdays,
+        start_time, end_time, valid_from, valid_until
+        FROM promotions
+        WHERE promotion_active_at(promotions, $1)
+        AND (product_id IS NULL OR product_id = $2)
+        AND (product_type IS NULL OR product_type = $3)
+        "#,
+        now,
+        product_id,
+        product_type as ProductType
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .filter(|promotion| promotion.unit_discount(unit_price) > 0)
+    .max_by_key(|promotion| promotion.unit_discount(unit_price));
+
+    Ok(best)
+}
@@ -1,8 +1,33 @@
-use async_graphql::{Context, ErrorExtensions, Object, Result};
+use async_graphql::{ComplexObject, Context, ErrorExtensions, Object, Result, Subscription};
+use futures_util::{Stream, StreamExt};
+use sqlx::{Pool, Postgres};
+use tracing::warn;
 
-use crate::db::{PrimaryKey, Purchase};
+use crate::{
+    db::{BalanceChange, PrimaryKey, Purchase, PurchaseModifier},
+    events::{self, Event},
+    metrics, rollup,
+};
 
-use super::extract_user_claims;
+use super::{
+    achievement, bundle, extract_admin_claims, extract_user_claims, modifier, price_list,
+    promotion, until_expired,
+};
+
+#[ComplexObject]
+impl Purchase {
+    async fn modifiers(&self, ctx: &Context<'_>) -> Result<Vec<PurchaseModifier>> {
+        let db = ctx.data()?;
+        sqlx::query_as!(
+            PurchaseModifier,
+            "SELECT * FROM purchase_modifiers WHERE purchase_id = $1",
+            self.id
+        )
+        .fetch_all(db)
+        .await
+        .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))
+    }
+}
 
 #[derive(Default)]
 pub struct PurchaseQuery;
@@ -62,72 +87,177 @@ impl PurchaseMutation {
         ctx: &Context<'_>,
         product_id: PrimaryKey,
         quantity: i32,
+        #[graphql(default)] modifier_option_ids: Vec<PrimaryKey>,
     ) -> Result<Purchase> {
         let user_claims = extract_user_claims(ctx)?;
         let db = ctx.data()?;
 
-        let product = sqlx::query!("SELECT price FROM products WHERE id = $1", product_id)
-            .fetch_one(db)
-            .await?;
+        // anything less would credit the balance and put units back into the stock
+        if quantity < 1 {
+            return Err(
+                async_graphql::Error::new("The quantity needs to be at least 1")
+                    .extend_with(|_, e| e.set("code", 400)),
+            );
+        }
+
+        let price = price_list::account_price(db, &user_claims.user_id, product_id).await?;
+        let modifiers = modifier::selected_modifiers(db, product_id, &modifier_option_ids).await?;
+        let unit_price =
+            (price.price + modifiers.iter().map(|m| m.price_delta).sum::<i64>()).max(0);
+
+        let promotion =
+            promotion::best_promotion(db, product_id, price.product_type, unit_price).await?;
+        let promotion_id = promotion.as_ref().map(|promotion| promotion.id);
+        let discount = promotion.map_or(0, |promotion| {
+            quantity as i64 * promotion.unit_discount(unit_price)
+        });
 
-        let paid_price: i64 = quantity as i64 * product.price;
+        let paid_price: i64 = quantity as i64 * unit_price - discount;
+        let deposit: i64 = quantity as i64 * price.deposit;
 
-        sqlx::query!(
-            "UPDATE accounts SET balance = balance - $2 WHERE id = $1",
+        let mut tx = db.begin().await?;
+
+        let balance = sqlx::query_scalar!(
+            "UPDATE accounts SET balance = balance - $2 WHERE id = $1 RETURNING balance",
             user_claims.user_id,
-            paid_price
+            paid_price + deposit
         )
-        .execute(db)
+        .fetch_one(&mut *tx)
         .await?;
 
         let purchase = sqlx::query_as!(
             Purchase,
             r#"
             INSERT INTO purchases
-            (account_id, product_id, quantity, paid_price)
-            VALUES ($1, $2, $3, $4)
+            (account_id, product_id, quantity, paid_price, promotion_id, discount, price_list_id,
+             deposit_type_id, deposit)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
             RETURNING *"#,
             user_claims.user_id,
             product_id,
             quantity,
-            paid_price
+            paid_price,
+            promotion_id,
+            discount,
+            price.price_list_id,
+            price.deposit_type_id,
+            deposit
         )
-        .fetch_one(db)
+        .fetch_one(&mut *tx)
         .await?;
 
+        for modifier in modifiers {
+            sqlx::query!(
+                r#"
+                INSERT INTO purchase_modifiers (purchase_id, modifier_option_id, name, price_delta)
+                VALUES ($1, $2, $3, $4)
+                "#,
+                purchase.id,
+                modifier.id,
+                modifier.name,
+                modifier.price_delta
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let stock_changed =
+            bundle::consume_stock(&mut tx, purchase.id, product_id, quantity).await?;
+        rollup::add_purchase(&mut tx, purchase.id).await?;
+
+        tx.commit().await?;
+
+        metrics::PURCHASED_UNITS.inc_by(quantity.max(0) as u64);
+        metrics::REVENUE.inc_by(paid_price.max(0) as u64);
+
+        events::publish(Event::BalanceChanged(BalanceChange {
+            account_id: user_claims.user_id.clone(),
+            balance,
+        }));
+        events::publish(Event::PurchaseMade(purchase.clone()));
+        for product_id in stock_changed {
+            events::publish_product_update(db, product_id).await;
+        }
+
+        // the purchase went through already, so a failure here shouldn't fail it
+        if let Err(err) = achievement::award_achievements(db, &user_claims.user_id).await {
+            warn!(
+                "Couldn't award achievements to {}: {}",
+                user_claims.user_id, err.message
+            );
+        }
+
         Ok(purchase)
     }
 
     async fn refund_purchase(&self, ctx: &Context<'_>, id: PrimaryKey) -> Result<bool> {
         let user_claims = extract_user_claims(ctx)?;
-        let db = ctx.data()?;
+        let db: &Pool<Postgres> = ctx.data()?;
+
+        let mut tx = db.begin().await?;
 
         let purchase = sqlx::query!(
             r#"
             UPDATE purchases
-            SET refunded = true
+            SET refunded = true, refunded_at = now()
             WHERE id = $1 AND refunded = false
-            RETURNING paid_price"#,
+            RETURNING paid_price, deposit, product_id, quantity"#,
             id
         )
-        .fetch_one(db)
+        .fetch_one(&mut *tx)
         .await
         .map_err(|_err| {
             async_graphql::Error::new("No not-yet refunded purchase with this id found")
         })?;
 
-        sqlx::query!(
+        let balance = sqlx::query_scalar!(
             r#"
             UPDATE accounts
             SET balance = balance + $1
             WHERE id = $2
+            RETURNING balance
             "#,
-            purchase.paid_price,
+            purchase.paid_price + purchase.deposit,
             user_claims.user_id
         )
-        .execute(db)
+        .fetch_one(&mut *tx)
         .await?;
 
+        let stock_changed =
+            bundle::restore_stock(&mut tx, id, purchase.product_id, purchase.quantity).await?;
+        rollup::remove_purchase(&mut tx, id).await?;
+
+        tx.commit().await?;
+
+        metrics::REFUNDED_UNITS.inc_by(purchase.quantity.max(0) as u64);
+        metrics::REFUNDED_REVENUE.inc_by(purchase.paid_price.max(0) as u64);
+
+        events::publish(Event::BalanceChanged(BalanceChange {
+            account_id: user_claims.user_id.clone(),
+            balance,
+        }));
+        for product_id in stock_changed {
+            events::publish_product_update(db, product_id).await;
+        }
+
         Ok(true)
     }
 }
+
+#[derive(Default)]
+pub struct PurchaseSubscription;
+
+#[Subscription]
+impl PurchaseSubscription {
+    /// Every purchase as it is made, for admin pages and the TV display
+    async fn purchase_made(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = Purchase>> {
+        let user_claims = extract_admin_claims(ctx)?;
+        let purchases = events::subscribe().filter_map(|event| async move {
+            match event {
+                Event::PurchaseMade(purchase) => Some(purchase),
+                _ => None,
+            }
+        });
+        Ok(until_expired(purchases, user_claims))
+    }
+}
@@ -1,17 +1,419 @@
-use async_graphql::{Object, Result};
+use std::{collections::BTreeMap, str::FromStr};
 
-use crate::db::{AccountPurchaseCount, ProductPurchaseCount};
+use async_graphql::{ComplexObject, Context, Enum, ErrorExtensions, Object, Result, SimpleObject};
+use chrono::{Datelike, Days, Months, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+
+use crate::{
+    auth::UserClaims,
+    db::{AccountPurchaseCount, Picture, PrimaryKey, Product, ProductPurchaseCount, ProductType},
+    picture::full_account_picture_key,
+    rollup::{self, RollupRange, DEFAULT_TIMEZONE},
+};
+
+use super::{extract_admin_claims, types::period::Period};
+
+/// Upper bound for the `limit` argument of the statistics
+const MAX_LIMIT: i64 = 100;
+
+/// Upper bound for the amount of buckets in a time series
+const MAX_BUCKETS: usize = 1000;
+
+/// Parses an IANA timezone name, falling back to the configured default timezone
+pub fn parse_timezone(timezone: Option<String>) -> Result<(String, Tz)> {
+    let timezone = timezone.unwrap_or_else(|| DEFAULT_TIMEZONE.clone());
+    let tz = Tz::from_str(&timezone)
+        .map_err(|_| async_graphql::Error::new(format!("Unknown timezone {timezone}")))?;
+    Ok((timezone, tz))
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum Granularity {
+    Day,
+    /// Weeks start on monday
+    Week,
+    Month,
+}
+
+impl Granularity {
+    /// Start of the bucket `time` falls into
+    fn truncate(self, time: NaiveDateTime) -> NaiveDateTime {
+        let date = time.date();
+        let date = match self {
+            Granularity::Day => date,
+            Granularity::Week => date - Days::new(date.weekday().num_days_from_monday() as u64),
+            Granularity::Month => NaiveDate::from_ymd_opt(date.year(), date.month(), 1).unwrap(),
+        };
+        date.and_hms_opt(0, 0, 0).unwrap()
+    }
+
+    fn next(self, bucket: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Granularity::Day => bucket + Days::new(1),
+            Granularity::Week => bucket + Days::new(7),
+            Granularity::Month => bucket + Months::new(1),
+        }
+    }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum SeriesGrouping {
+    /// One series with all sales
+    Total,
+    /// One series per product type
+    ProductType,
+    /// One series per product
+    Product,
+}
+
+#[derive(SimpleObject)]
+struct SalesTimeSeries {
+    /// Timezone the buckets are in
+    timezone: String,
+    granularity: Granularity,
+    /// Start of every bucket, the points of every series line up with these
+    buckets: Vec<NaiveDateTime>,
+    series: Vec<SalesSeries>,
+}
+
+#[derive(SimpleObject)]
+struct SalesSeries {
+    label: String,
+    /// Set if grouped by product
+    product_id: Option<PrimaryKey>,
+    /// Set if grouped by product or product type
+    product_type: Option<ProductType>,
+    points: Vec<SalesPoint>,
+}
+
+#[derive(SimpleObject, Clone)]
+struct SalesPoint {
+    bucket: NaiveDateTime,
+    units: i64,
+    /// Paid prices, without deposits
+    revenue: i64,
+}
+
+#[ComplexObject]
+impl AccountPurchaseCount {
+    async fn picture(&self) -> Option<Picture> {
+        self.picture
+            .clone()
+            .map(|key| Picture::new(key, full_account_picture_key))
+    }
+}
 
 #[derive(Default)]
 pub struct StatisticsQuery;
 
 #[Object]
 impl StatisticsQuery {
-    async fn purchase_counts_last_month(&self) -> Result<Vec<ProductPurchaseCount>> {
-        todo!()
+    /// The most bought products in the given period, refunds are not counted.
+    /// Despite the name, the period defaults to, but isn't limited to, the last month.
+    async fn purchase_counts_last_month(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] period: Period,
+        product_type: Option<ProductType>,
+        #[graphql(default = 10)] limit: i64,
+    ) -> Result<Vec<ProductPurchaseCount>> {
+        let db = ctx.data()?;
+        let (timezone, tz) = parse_timezone(None)?;
+        let now = Utc::now().with_timezone(&tz).naive_local();
+        let (from, to) = period.range_until(now)?;
+        let range = RollupRange::new(from, to, &timezone);
+
+        // purchases store the wall clock time of the database, which the bounds are converted to
+        let counts = sqlx::query!(
+            r#"
+            SELECT products.id, products.name, products.price, products.picture,
+            products.product_type AS "product_type: ProductType",
+            products.deposit_type_id, products.stock, counts.count AS "count!"
+            FROM (
+                SELECT sales.product_id, SUM(sales.units)::INT AS count
+                FROM (
+                    SELECT product_id, units FROM daily_product_sales
+                    WHERE day >= $1 AND day < $2
+                    UNION ALL
+                    SELECT product_id, quantity FROM purchases
+                    WHERE NOT refunded AND (
+                        (created_at >= ($3::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP
+                            AND created_at < ($4::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP)
+                        OR (created_at >= ($5::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP
+                            AND created_at < ($6::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP)
+                    )
+                ) AS sales
+                JOIN products ON products.id = sales.product_id
+                WHERE $8::product_type IS NULL OR products.product_type = $8
+                GROUP BY sales.product_id
+            ) AS counts
+            JOIN products ON products.id = counts.product_id
+            WHERE counts.count > 0
+            ORDER BY counts.count DESC, products.name ASC
+            LIMIT $9
+            "#,
+            range.days.0,
+            range.days.1,
+            range.head.0,
+            range.head.1,
+            range.tail.0,
+            range.tail.1,
+            timezone,
+            product_type as Option<ProductType>,
+            limit.clamp(1, MAX_LIMIT)
+        )
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(|row| ProductPurchaseCount {
+            id: row.id,
+            product: Product {
+                id: row.id,
+                name: row.name,
+                product_type: row.product_type,
+                price: row.price,
+                picture: row.picture,
+                deposit_type_id: row.deposit_type_id,
+                stock: row.stock,
+            },
+            count: row.count,
+        })
+        .collect();
+
+        Ok(counts)
+    }
+
+    /// The accounts which bought the most in the given period, refunds are not counted.
+    /// Hidden accounts are left out and anonymous ones stripped of their identity,
+    /// unless an admin is asking.
+    async fn leaderboard(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] period: Period,
+        product_type: Option<ProductType>,
+        #[graphql(default = 10)] limit: i64,
+    ) -> Result<Vec<AccountPurchaseCount>> {
+        let db = ctx.data()?;
+        let (timezone, tz) = parse_timezone(None)?;
+        let now = Utc::now().with_timezone(&tz).naive_local();
+        let (from, to) = period.range_until(now)?;
+        let range = RollupRange::new(from, to, &timezone);
+
+        let is_admin = ctx
+            .data::<UserClaims>()
+            .is_ok_and(|user_claims| user_claims.is_admin());
+
+        // purchases store the wall clock time of the database, which the bounds are converted to
+        let leaderboard = sqlx::query_as!(
+            AccountPurchaseCount,
+            r#"
+            SELECT accounts.id, accounts.name, accounts.picture, counts.count AS "count!",
+            (NOT $10 AND account_settings.leaderboard_visibility = 'anonymous') IS TRUE
+                AS "anonymous!"
+            FROM (
+                SELECT sales.account_id, SUM(sales.units)::INT AS count
+                FROM (
+                    SELECT account_id, units FROM daily_account_sales
+                    WHERE day >= $1 AND day < $2
+                    AND ($8::product_type IS NULL OR product_type = $8)
+                    UNION ALL
+                    SELECT purchases.account_id, purchases.quantity FROM purchases
+                    JOIN products ON products.id = purchases.product_id
+                    WHERE NOT purchases.refunded
+                    AND ($8::product_type IS NULL OR products.product_type = $8)
+                    AND (
+                        (purchases.created_at >= ($3::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP
+                            AND purchases.created_at < ($4::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP)
+                        OR (purchases.created_at >= ($5::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP
+                            AND purchases.created_at < ($6::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP)
+                    )
+                ) AS sales
+                GROUP BY sales.account_id
+            ) AS counts
+            JOIN accounts ON accounts.id = counts.account_id
+            LEFT JOIN account_settings ON account_settings.account_id = accounts.id
+            WHERE counts.count > 0 AND accounts.deleted_at IS NULL
+            AND ($10 OR account_settings.leaderboard_visibility IS DISTINCT FROM 'hidden')
+            ORDER BY counts.count DESC, accounts.name ASC
+            LIMIT $9
+            "#,
+            range.days.0,
+            range.days.1,
+            range.head.0,
+            range.head.1,
+            range.tail.0,
+            range.tail.1,
+            timezone,
+            product_type as Option<ProductType>,
+            limit.clamp(1, MAX_LIMIT),
+            is_admin
+        )
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .enumerate()
+        .map(|(rank, entry)| {
+            if !entry.anonymous {
+                return entry;
+            }
+            AccountPurchaseCount {
+                id: format!("anonymous-{rank}"),
+                name: "Anonymous".to_string(),
+                picture: None,
+                ..entry
+            }
+        })
+        .collect();
+
+        Ok(leaderboard)
     }
 
-    async fn leaderboard(&self) -> Result<Vec<AccountPurchaseCount>> {
-        todo!()
+    /// Units sold and revenue over time, bucketed in the given timezone and without gaps.
+    /// Refunds are not counted.
+    async fn sales_time_series(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] period: Period,
+        granularity: Granularity,
+        #[graphql(default_with = "SeriesGrouping::Total")] group_by: SeriesGrouping,
+        product_type: Option<ProductType>,
+        #[graphql(desc = "IANA timezone, e.g. Europe/Berlin. Defaults to the configured one")]
+        timezone: Option<String>,
+    ) -> Result<SalesTimeSeries> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        let (timezone, tz) = parse_timezone(timezone)?;
+
+        let now = Utc::now().with_timezone(&tz).naive_local();
+        let (from, to) = period.range_until(now)?;
+
+        let mut buckets = Vec::new();
+        let mut bucket = granularity.truncate(from);
+        while bucket < to {
+            if buckets.len() == MAX_BUCKETS {
+                return Err(async_graphql::Error::new(format!(
+                    "Time series can have at most {MAX_BUCKETS} buckets, use a coarser granularity"
+                )));
+            }
+            buckets.push(bucket);
+            bucket = granularity.next(bucket);
+        }
+
+        let range = RollupRange::new(from, to, &timezone);
+
+        // purchases store the wall clock time of the database, which is converted to `tz` here
+        let rows = sqlx::query!(
+            r#"
+            SELECT sales.day AS "day!", products.id, products.name,
+            products.product_type AS "product_type: ProductType",
+            SUM(sales.units)::BIGINT AS "units!",
+            SUM(sales.revenue)::BIGINT AS "revenue!"
+            FROM (
+                SELECT day, product_id, units, revenue FROM daily_product_sales
+                WHERE day >= $1 AND day < $2
+                UNION ALL
+                SELECT (created_at::timestamptz AT TIME ZONE $7)::DATE, product_id,
+                quantity, paid_price
+                FROM purchases
+                WHERE NOT refunded AND (
+                    (created_at >= ($3::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP
+                        AND created_at < ($4::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP)
+                    OR (created_at >= ($5::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP
+                        AND created_at < ($6::TIMESTAMP AT TIME ZONE $7)::TIMESTAMP)
+                )
+            ) AS sales
+            JOIN products ON products.id = sales.product_id
+            WHERE $8::product_type IS NULL OR products.product_type = $8
+            GROUP BY sales.day, products.id, products.name, products.product_type
+            "#,
+            range.days.0,
+            range.days.1,
+            range.head.0,
+            range.head.1,
+            range.tail.0,
+            range.tail.1,
+            timezone,
+            product_type as Option<ProductType>
+        )
+        .fetch_all(db)
+        .await?;
+
+        let bucket_index: BTreeMap<NaiveDateTime, usize> = buckets
+            .iter()
+            .enumerate()
+            .map(|(index, bucket)| (*bucket, index))
+            .collect();
+        let empty_points: Vec<SalesPoint> = buckets
+            .iter()
+            .map(|bucket| SalesPoint {
+                bucket: *bucket,
+                units: 0,
+                revenue: 0,
+            })
+            .collect();
+
+        // keyed by label and product id, so the series are sorted by their label
+        let mut series: BTreeMap<(String, PrimaryKey), SalesSeries> = BTreeMap::new();
+        if group_by == SeriesGrouping::Total {
+            series.insert(
+                (String::new(), 0),
+                SalesSeries {
+                    label: "Total".to_string(),
+                    product_id: None,
+                    product_type: None,
+                    points: empty_points.clone(),
+                },
+            );
+        }
+
+        for row in rows {
+            let bucket = granularity.truncate(row.day.and_time(NaiveTime::MIN));
+            let Some(index) = bucket_index.get(&bucket) else {
+                continue;
+            };
+
+            let (label, product_id, product_type) = match group_by {
+                SeriesGrouping::Total => (String::new(), None, None),
+                SeriesGrouping::ProductType => (
+                    format!("{:?}", row.product_type),
+                    None,
+                    Some(row.product_type),
+                ),
+                SeriesGrouping::Product => (row.name, Some(row.id), Some(row.product_type)),
+            };
+
+            let key = (label.clone(), product_id.unwrap_or_default());
+            let entry = series.entry(key).or_insert_with(|| SalesSeries {
+                label,
+                product_id,
+                product_type,
+                points: empty_points.clone(),
+            });
+            entry.points[*index].units += row.units;
+            entry.points[*index].revenue += row.revenue;
+        }
+
+        Ok(SalesTimeSeries {
+            timezone,
+            granularity,
+            buckets,
+            series: series.into_values().collect(),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct StatisticsMutation;
+
+#[Object]
+impl StatisticsMutation {
+    /// Builds the daily rollups the statistics are read from again from the purchases
+    async fn rebuild_statistics_rollups(&self, ctx: &Context<'_>) -> Result<bool> {
+        extract_admin_claims(ctx)?;
+        let db = ctx.data()?;
+        rollup::rebuild(db)
+            .await
+            .map_err(|err| err.extend_with(|_, e| e.set("code", 500)))?;
+        Ok(true)
     }
 }
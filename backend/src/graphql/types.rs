@@ -20,3 +20,55 @@ pub mod sort {
         Descending,
     }
 }
+
+pub mod period {
+    use async_graphql::{Enum, InputObject};
+    use chrono::{Duration, Months, NaiveDateTime};
+
+    #[derive(Enum, Copy, Clone, Eq, PartialEq, Default)]
+    pub enum TimeWindow {
+        Week,
+        #[default]
+        Month,
+        Year,
+        /// Uses `from` and `to` of the period
+        Custom,
+    }
+
+    /// A time range ending now, or a custom range
+    #[derive(InputObject, Default)]
+    pub struct Period {
+        #[graphql(default)]
+        pub window: TimeWindow,
+        pub from: Option<NaiveDateTime>,
+        pub to: Option<NaiveDateTime>,
+    }
+
+    impl Period {
+        /// Start (inclusive) and end (exclusive) of this period,
+        /// with `now` as the end of the non-custom windows
+        pub fn range_until(
+            &self,
+            now: NaiveDateTime,
+        ) -> async_graphql::Result<(NaiveDateTime, NaiveDateTime)> {
+            let range = match self.window {
+                TimeWindow::Week => (now - Duration::days(7), now),
+                TimeWindow::Month => (now - Months::new(1), now),
+                TimeWindow::Year => (now - Months::new(12), now),
+                TimeWindow::Custom => {
+                    let (Some(from), Some(to)) = (self.from, self.to) else {
+                        return Err(async_graphql::Error::new(
+                            "A custom period needs both from and to",
+                        ));
+                    };
+                    (from, to)
+                }
+            };
+
+            if range.0 > range.1 {
+                return Err(async_graphql::Error::new("from needs to be before to"));
+            }
+            Ok(range)
+        }
+    }
+}
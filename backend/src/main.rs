@@ -1,8 +1,11 @@
 use crate::{
     config::SETTINGS,
-    rest::pictureapi::{AccountPicApi, ProductPicApi},
+    rest::{
+        exportapi::ExportApi,
+        pictureapi::{picture_file_handler, AccountPicApi, PictureGcApi, ProductPicApi},
+    },
 };
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
 use poem::{get, listener::TcpListener, middleware::Cors, post, EndpointExt, Route, Server};
 use poem_openapi::OpenApiService;
 use sqlx::postgres::PgPoolOptions;
@@ -12,9 +15,13 @@ use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
 mod auth;
 mod config;
 mod db;
+mod events;
 mod graphql;
+mod metrics;
+mod picture;
 mod rest;
-mod s3;
+mod rollup;
+mod storage;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -32,7 +39,15 @@ async fn main() -> Result<()> {
     let auth_server_url = SETTINGS.get_string("auth.provider.url")?;
     auth::setup(&auth_server_url).await?;
 
-    s3::init().await;
+    storage::init().await?;
+    picture::init()?;
+    rest::exportapi::init()?;
+
+    // the rollups split days in it inside the database, where a bad one fails every purchase
+    let timezone = SETTINGS.get_string("statistics.timezone")?;
+    timezone
+        .parse::<chrono_tz::Tz>()
+        .map_err(|err| eyre!("statistics.timezone {timezone} is no valid timezone: {err}"))?;
 
     let port = SETTINGS.get_int("port")?;
     let ip = SETTINGS.get_string("ip")?;
@@ -42,9 +57,10 @@ async fn main() -> Result<()> {
 
     info!("OpenAPI explorer running at {hosted_http}/q/docs");
     info!("GraphiQL running at {hosted_http}/q/graphiql");
+    info!("Prometheus metrics at {hosted_http}/q/metrics");
     info!("Login endpoint at {auth_server_url}/account/#/");
 
-    let all_endpoints = (AccountPicApi, ProductPicApi);
+    let all_endpoints = (AccountPicApi, ProductPicApi, PictureGcApi, ExportApi);
 
     let api_service = OpenApiService::new(all_endpoints, "Ruscalimat API", "1.0")
         .server(format!("{hosted_http}/v1/rest"));
@@ -60,16 +76,25 @@ async fn main() -> Result<()> {
         .await?;
 
     sqlx::migrate!().run(&db_pool).await?;
+    rollup::rebuild_if_stale(&db_pool).await?;
+    tokio::spawn(picture::gc::run_periodically(db_pool.clone()));
+    tokio::spawn(picture::thumbnails::backfill(db_pool.clone()));
+
+    let schema = graphql::build_schema(&db_pool)?;
+    let max_batch_size = graphql::MaxBatchSize::from_settings()?;
 
     let dev_paths = Route::new()
         .nest("/graphiql", get(graphql::graphiql_handler))
         .nest("/docs", ui)
         .nest("/openapi", openapi_spec)
-        .nest("/openapi.yaml", openapi_spec_yaml);
+        .nest("/openapi.yaml", openapi_spec_yaml)
+        .at("/metrics", get(metrics::metrics_handler));
 
     let api_routes = Route::new()
         .nest("/rest", api_service)
         .at("/graphql", post(graphql::graphql_handler))
+        .at("/graphql/ws", get(graphql::graphql_ws_handler))
+        .at("/pictures/*key", get(picture_file_handler))
         .with(Cors::new())
         .with(poem::middleware::Tracing);
 
@@ -78,7 +103,10 @@ async fn main() -> Result<()> {
             "/ruscalimat",
             Route::new().nest("/v1", api_routes).nest("/q", dev_paths),
         )
-        .data(db_pool);
+        .with(metrics::HttpMetrics)
+        .data(db_pool)
+        .data(schema)
+        .data(max_batch_size);
 
     Server::new(TcpListener::bind(hosted_url)).run(app).await?;
 
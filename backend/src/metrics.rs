@@ -0,0 +1,253 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use async_graphql::{
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextExecute, NextParseQuery},
+    parser::types::{ExecutableDocument, OperationType},
+    Response as GraphQLResponse, ServerResult, Variables,
+};
+use once_cell::sync::Lazy;
+use poem::{
+    handler, web::Data, Endpoint, IntoResponse, Middleware, PathPattern, Request, Response, Result,
+};
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use sqlx::{Pool, Postgres};
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ruscalimat_http_requests_total",
+        "HTTP requests by method, route and status code",
+        &["method", "route", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "ruscalimat_http_request_duration_seconds",
+        "HTTP request latency by method and route",
+        &["method", "route"]
+    )
+    .unwrap()
+});
+
+static GRAPHQL_OPERATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ruscalimat_graphql_operations_total",
+        "GraphQL operations by operation type and outcome",
+        &["type", "outcome"]
+    )
+    .unwrap()
+});
+
+static GRAPHQL_OPERATION_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "ruscalimat_graphql_operation_duration_seconds",
+        "GraphQL operation latency by operation type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+static DB_POOL_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "ruscalimat_db_pool_connections",
+        "Database pool connections by state, max is the configured pool size",
+        &["state"]
+    )
+    .unwrap()
+});
+
+pub static S3_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ruscalimat_s3_errors_total",
+        "Failed S3 calls by operation",
+        &["operation"]
+    )
+    .unwrap()
+});
+
+pub static PURCHASED_UNITS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "ruscalimat_purchased_units_total",
+        "Units bought, refunds are counted separately"
+    )
+    .unwrap()
+});
+
+pub static REVENUE: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "ruscalimat_revenue_cents_total",
+        "Paid prices of purchases in cents without deposits, refunds are counted separately"
+    )
+    .unwrap()
+});
+
+pub static REFUNDED_UNITS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("ruscalimat_refunded_units_total", "Units refunded").unwrap()
+});
+
+pub static REFUNDED_REVENUE: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "ruscalimat_refunded_revenue_cents_total",
+        "Paid prices of refunded purchases in cents, without deposits"
+    )
+    .unwrap()
+});
+
+pub static PIN_LOGIN_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "ruscalimat_pin_login_failures_total",
+        "Pin logins with a wrong pin or unknown account"
+    )
+    .unwrap()
+});
+
+/// Serves all metrics in the Prometheus text format
+#[handler]
+pub async fn metrics_handler(Data(db_pool): Data<&Pool<Postgres>>) -> Result<impl IntoResponse> {
+    let idle = db_pool.num_idle() as i64;
+    let size = db_pool.size() as i64;
+    DB_POOL_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+    DB_POOL_CONNECTIONS
+        .with_label_values(&["in_use"])
+        .set(size - idle);
+    DB_POOL_CONNECTIONS
+        .with_label_values(&["max"])
+        .set(db_pool.options().get_max_connections() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut body)
+        .map_err(poem::error::InternalServerError)?;
+
+    Ok(body.with_content_type(encoder.format_type()))
+}
+
+/// Records the count and latency of HTTP requests, labeled with the matched route
+/// pattern instead of the path, so ids don't end up in the labels
+pub struct HttpMetrics;
+
+impl<E: Endpoint> Middleware<E> for HttpMetrics {
+    type Output = HttpMetricsEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        HttpMetricsEndpoint { inner: ep }
+    }
+}
+
+pub struct HttpMetricsEndpoint<E> {
+    inner: E,
+}
+
+#[poem::async_trait]
+impl<E: Endpoint> Endpoint for HttpMetricsEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let method = req.method().to_string();
+        let start = Instant::now();
+        let res = self.inner.call(req).await.map(IntoResponse::into_response);
+        let elapsed = start.elapsed();
+
+        let (route, status) = match &res {
+            Ok(resp) => (resp.data::<PathPattern>(), resp.status()),
+            Err(err) => (err.data::<PathPattern>(), err.status()),
+        };
+        let route = route.map_or("unmatched", |pattern| &pattern.0);
+
+        HTTP_REQUESTS
+            .with_label_values(&[&method, route, status.as_str()])
+            .inc();
+        HTTP_REQUEST_DURATION
+            .with_label_values(&[&method, route])
+            .observe(elapsed.as_secs_f64());
+
+        res
+    }
+}
+
+/// Records the count, outcome and latency of GraphQL operations, labeled with the
+/// operation type since operation names are picked by the client
+pub struct GraphQLMetrics;
+
+impl ExtensionFactory for GraphQLMetrics {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(GraphQLMetricsExtension::default())
+    }
+}
+
+#[derive(Default)]
+struct GraphQLMetricsExtension {
+    /// Types of the operations in the parsed document, by operation name
+    operation_types: Mutex<Vec<(Option<String>, OperationType)>>,
+}
+
+impl GraphQLMetricsExtension {
+    fn operation_type(&self, operation_name: Option<&str>) -> &'static str {
+        let operation_types = self.operation_types.lock().unwrap();
+        let operation_type = match operation_name {
+            Some(name) => operation_types
+                .iter()
+                .find(|(op_name, _)| op_name.as_deref() == Some(name))
+                .map(|(_, ty)| *ty),
+            None if operation_types.len() == 1 => Some(operation_types[0].1),
+            None => None,
+        };
+
+        match operation_type {
+            Some(OperationType::Query) => "query",
+            Some(OperationType::Mutation) => "mutation",
+            Some(OperationType::Subscription) => "subscription",
+            None => "unknown",
+        }
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Extension for GraphQLMetricsExtension {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await?;
+        *self.operation_types.lock().unwrap() = document
+            .operations
+            .iter()
+            .map(|(name, operation)| (name.map(|name| name.to_string()), operation.node.ty))
+            .collect();
+        Ok(document)
+    }
+
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> GraphQLResponse {
+        let start = Instant::now();
+        let response = next.run(ctx, operation_name).await;
+        let elapsed = start.elapsed();
+
+        let operation_type = self.operation_type(operation_name);
+        let outcome = if response.is_ok() { "ok" } else { "error" };
+
+        GRAPHQL_OPERATIONS
+            .with_label_values(&[operation_type, outcome])
+            .inc();
+        GRAPHQL_OPERATION_DURATION
+            .with_label_values(&[operation_type])
+            .observe(elapsed.as_secs_f64());
+
+        response
+    }
+}
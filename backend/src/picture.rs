@@ -0,0 +1,339 @@
+//! Normalizes uploaded pictures. Uploads are recognized by their content instead of
+//! their filename, turned upright according to their EXIF orientation and encoded
+//! again, which leaves all metadata behind. Every picture is stored in a few sizes,
+//! so the kiosk doesn't have to load full phone photos for its avatars.
+
+use std::{
+    io::{BufRead, Seek},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use color_eyre::eyre::{bail, ensure};
+use exif::{In, Tag};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    imageops::FilterType,
+    io::{Limits, Reader},
+    DynamicImage, ImageError, ImageFormat,
+};
+use poem::{
+    error::{InternalServerError, ResponseError},
+    http::StatusCode,
+    Result,
+};
+use tracing::warn;
+
+use uuid::Uuid;
+
+use crate::{
+    config::SETTINGS,
+    storage::{storage, StorageError},
+};
+
+pub mod avatar;
+pub mod gallery;
+pub mod gc;
+pub mod import;
+pub mod thumbnails;
+
+/// Longest edge of the stored picture in pixels, larger pictures are scaled down
+pub const MAX_SIZE: u32 = 1024;
+
+/// Longest edges of the scaled down copies, smallest first
+pub const THUMBNAIL_SIZES: &[u32] = &[64, 256];
+
+const ACCEPTED_FORMATS: &[ImageFormat] = &[ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP];
+
+/// Uploads larger than this in either dimension are rejected before decoding
+const MAX_INPUT_DIMENSION: u32 = 8_192;
+
+/// Upper bound for the memory the decoder may allocate, in bytes
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+
+const JPEG_QUALITY: u8 = 85;
+
+enum UrlMode {
+    /// Pictures are served by the backend
+    Proxy,
+    /// Pictures are fetched straight from the storage, if it supports that
+    Presigned,
+}
+
+struct PictureConfig {
+    url_mode: UrlMode,
+    public_url: String,
+    presigned_expiry: Duration,
+}
+
+static CONFIG: OnceLock<PictureConfig> = OnceLock::new();
+
+/// Loads the `pictures` section of the config, called at startup so a broken config
+/// fails there instead of on the first picture url
+pub fn init() -> color_eyre::Result<()> {
+    let url_mode = match SETTINGS.get_string("pictures.url_mode")?.as_str() {
+        "proxy" => UrlMode::Proxy,
+        "presigned" => UrlMode::Presigned,
+        mode => bail!("Unknown pictures.url_mode {mode}, expected proxy or presigned"),
+    };
+    let presigned_expiry_seconds = SETTINGS.get_int("pictures.presigned_expiry_seconds")?;
+    ensure!(
+        presigned_expiry_seconds >= 0,
+        "pictures.presigned_expiry_seconds can't be negative, got {presigned_expiry_seconds}"
+    );
+
+    let config = PictureConfig {
+        url_mode,
+        public_url: SETTINGS.get_string("pictures.public_url")?,
+        presigned_expiry: Duration::from_secs(presigned_expiry_seconds as u64),
+    };
+
+    if CONFIG.set(config).is_err() {
+        panic!("Picture config initialized twice");
+    }
+    import::init()
+}
+
+fn config() -> &'static PictureConfig {
+    CONFIG.get().expect("Picture config isn't initialized")
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PictureError {
+    #[error("Unsupported picture format, supported are JPEG, PNG and WebP")]
+    UnsupportedFormat,
+    #[error("Invalid picture: {0}")]
+    Invalid(ImageError),
+    #[error("Couldn't encode picture: {0}")]
+    Encode(ImageError),
+}
+
+impl ResponseError for PictureError {
+    fn status(&self) -> StatusCode {
+        match self {
+            PictureError::UnsupportedFormat | PictureError::Invalid(_) => StatusCode::BAD_REQUEST,
+            PictureError::Encode(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+pub struct ProcessedPicture {
+    pub extension: &'static str,
+    pub content_type: &'static str,
+    /// The picture scaled down to at most `MAX_SIZE`
+    pub full: Vec<u8>,
+    /// One copy for every entry of `THUMBNAIL_SIZES`
+    pub thumbnails: Vec<(u32, Vec<u8>)>,
+}
+
+/// Decodes an upload and encodes it again in all sizes. Pictures with transparent
+/// pixels become PNGs, everything else JPEGs.
+/// This is CPU heavy, so it should run on a blocking thread.
+pub fn process(picture: impl BufRead + Seek) -> Result<ProcessedPicture, PictureError> {
+    let image = decode(picture)?;
+
+    let (extension, content_type) = if has_transparency(&image) {
+        ("png", "image/png")
+    } else {
+        ("jpg", "image/jpeg")
+    };
+    let png = extension == "png";
+
+    let full = encode(&scale_down(&image, MAX_SIZE), png)?;
+    let thumbnails = encode_thumbnails(&image, png)?;
+
+    Ok(ProcessedPicture {
+        extension,
+        content_type,
+        full,
+        thumbnails,
+    })
+}
+
+/// Scales an already stored picture down to all of `THUMBNAIL_SIZES`, encoded as
+/// PNG or JPEG. This is CPU heavy, so it should run on a blocking thread.
+pub fn process_thumbnails(
+    picture: impl BufRead + Seek,
+    png: bool,
+) -> Result<Vec<(u32, Vec<u8>)>, PictureError> {
+    encode_thumbnails(&decode(picture)?, png)
+}
+
+/// Decodes a picture in one of the accepted formats and turns it upright
+fn decode(picture: impl BufRead + Seek) -> Result<DynamicImage, PictureError> {
+    let reader = Reader::new(picture)
+        .with_guessed_format()
+        .map_err(|err| PictureError::Invalid(err.into()))?;
+    let format = reader
+        .format()
+        .filter(|format| ACCEPTED_FORMATS.contains(format))
+        .ok_or(PictureError::UnsupportedFormat)?;
+
+    let mut picture = reader.into_inner();
+    let orientation = orientation(&mut picture);
+    picture
+        .rewind()
+        .map_err(|err| PictureError::Invalid(err.into()))?;
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_INPUT_DIMENSION);
+    limits.max_image_height = Some(MAX_INPUT_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+
+    let mut reader = Reader::with_format(picture, format);
+    reader.limits(limits);
+    let image = reader.decode().map_err(PictureError::Invalid)?;
+
+    Ok(apply_orientation(image, orientation))
+}
+
+fn encode_thumbnails(image: &DynamicImage, png: bool) -> Result<Vec<(u32, Vec<u8>)>, PictureError> {
+    THUMBNAIL_SIZES
+        .iter()
+        .map(|&size| Ok((size, encode(&scale_down(image, size), png)?)))
+        .collect()
+}
+
+/// The partial key of a scaled down copy of the picture stored under `part_key`
+pub fn thumbnail_key(part_key: &str, size: u32) -> String {
+    format!("{size}/{part_key}")
+}
+
+/// Normalizes a picture and stores it in all sizes.
+/// `full_key` turns the partial key into the account or product specific one.
+/// Returns the partial key, which goes into the database.
+pub async fn store_picture(
+    picture: impl BufRead + Seek + Send + 'static,
+    id: &str,
+    full_key: fn(&str) -> String,
+) -> Result<String> {
+    let processed = tokio::task::spawn_blocking(move || process(picture))
+        .await
+        .map_err(InternalServerError)??;
+
+    let part_key = partial_picture_key(processed.extension, id);
+
+    for (size, thumbnail) in processed.thumbnails {
+        let key = full_key(&thumbnail_key(&part_key, size));
+        storage()
+            .upload_file(&key, thumbnail, processed.content_type)
+            .await
+            .map_err(InternalServerError)?;
+    }
+    storage()
+        .upload_file(&full_key(&part_key), processed.full, processed.content_type)
+        .await
+        .map_err(InternalServerError)?;
+
+    Ok(part_key)
+}
+
+/// Deletes a picture in all sizes, after the database stopped pointing to it.
+/// Failures are only logged, the garbage collection picks up what is left behind.
+pub async fn delete_picture(part_key: &str, full_key: fn(&str) -> String) {
+    let keys = std::iter::once(full_key(part_key)).chain(
+        THUMBNAIL_SIZES
+            .iter()
+            .map(|&size| full_key(&thumbnail_key(part_key, size))),
+    );
+    for key in keys {
+        if let Err(err) = storage().delete_file(&key).await {
+            warn!("Couldn't delete picture {key}, leaving it to the garbage collection: {err}");
+        }
+    }
+}
+
+/// This calculates the part of the storage key which is independent from
+/// the usage, i.e. this has nothing to indicate whether or not it's an
+/// account or a product picture.
+pub fn partial_picture_key(extension: &str, user_id: &str) -> String {
+    format!("{}_{user_id}.{extension}", Uuid::new_v4())
+}
+
+/// turn a generic key into a account-specific one
+pub fn full_account_picture_key(part_key: &str) -> String {
+    format!("account/{part_key}")
+}
+
+/// turn a generic key into a product-specific one
+pub fn full_product_picture_key(part_key: &str) -> String {
+    format!("product/{part_key}")
+}
+
+/// A url the picture stored under `full_key` can be fetched from.
+/// Storages without presigned urls fall back to the proxy.
+pub async fn url(full_key: &str) -> Result<String, StorageError> {
+    let config = config();
+    if let UrlMode::Presigned = config.url_mode {
+        if let Some(url) = storage()
+            .presigned_url(full_key, config.presigned_expiry)
+            .await?
+        {
+            return Ok(url);
+        }
+    }
+
+    Ok(format!(
+        "{}/v1/pictures/{full_key}",
+        config.public_url.trim_end_matches('/')
+    ))
+}
+
+/// Where the picture of an account can be fetched from, which is a generated
+/// avatar if the account has no uploaded picture
+pub fn avatar_url(account_id: &str) -> String {
+    format!(
+        "{}/v1/rest/picture/account/{account_id}/avatar",
+        config().public_url.trim_end_matches('/')
+    )
+}
+
+/// The EXIF orientation, 1 (upright) if the picture has none
+fn orientation(picture: &mut (impl BufRead + Seek)) -> u32 {
+    exif::Reader::new()
+        .read_from_container(picture)
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(Tag::Orientation, In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+fn has_transparency(image: &DynamicImage) -> bool {
+    image.color().has_alpha() && image.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX)
+}
+
+/// Keeps the aspect ratio, pictures are never scaled up
+fn scale_down(image: &DynamicImage, size: u32) -> DynamicImage {
+    if image.width() <= size && image.height() <= size {
+        image.clone()
+    } else {
+        image.resize(size, size, FilterType::Lanczos3)
+    }
+}
+
+fn encode(image: &DynamicImage, png: bool) -> Result<Vec<u8>, PictureError> {
+    let mut buffer = Vec::new();
+    let result = if png {
+        DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(PngEncoder::new(&mut buffer))
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, JPEG_QUALITY))
+    };
+    result.map_err(PictureError::Encode)?;
+    Ok(buffer)
+}
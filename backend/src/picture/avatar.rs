@@ -0,0 +1,109 @@
+//! Generated avatars for accounts without an uploaded picture. Everything is derived
+//! from the account id, so an account keeps its avatar and color across restarts and
+//! versions; that's why this hashes with FNV instead of the std hasher.
+
+use image::{codecs::png::PngEncoder, DynamicImage, ImageResult, Rgb, RgbImage};
+
+use super::THUMBNAIL_SIZES;
+
+const BACKGROUND: Rgb<u8> = Rgb([240, 240, 240]);
+
+/// The initials of `name` on a color derived from the account id
+pub fn svg(account_id: &str, name: &str) -> String {
+    let Rgb([r, g, b]) = color(fnv1a(account_id));
+    let initials = escape_xml(&initials(name));
+    format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 100">"#,
+            r##"<rect width="100" height="100" fill="#{r:02x}{g:02x}{b:02x}"/>"##,
+            r#"<text x="50" y="50" dy=".35em" text-anchor="middle" font-family="sans-serif" "#,
+            r##"font-size="40" font-weight="bold" fill="#ffffff">{initials}</text></svg>"##,
+        ),
+        r = r,
+        g = g,
+        b = b,
+        initials = initials
+    )
+}
+
+/// A symmetric 5x5 identicon derived from the account id. Like uploaded pictures it
+/// comes in the smallest thumbnail size at least `size` pixels large, or the largest one,
+/// so a request can't make us render a huge PNG.
+pub fn identicon_png(account_id: &str, size: u32) -> ImageResult<Vec<u8>> {
+    let size = THUMBNAIL_SIZES
+        .iter()
+        .copied()
+        .find(|&thumbnail| thumbnail >= size)
+        .unwrap_or(THUMBNAIL_SIZES[THUMBNAIL_SIZES.len() - 1]);
+    let hash = fnv1a(account_id);
+    let foreground = color(hash);
+
+    // the cells have half a cell of margin around them
+    let cell = size as f32 / 6.0;
+    let image = RgbImage::from_fn(size, size, |x, y| {
+        let column = ((x as f32 - cell / 2.0) / cell).floor();
+        let row = ((y as f32 - cell / 2.0) / cell).floor();
+        if !(0.0..5.0).contains(&column) || !(0.0..5.0).contains(&row) {
+            return BACKGROUND;
+        }
+
+        // the right two columns mirror the left two
+        let column = (column as u64).min(4 - column as u64);
+        let bit = row as u64 * 3 + column;
+        // the lowest bits already picked the color
+        if hash >> (16 + bit) & 1 == 1 {
+            foreground
+        } else {
+            BACKGROUND
+        }
+    });
+
+    let mut buffer = Vec::new();
+    DynamicImage::ImageRgb8(image).write_with_encoder(PngEncoder::new(&mut buffer))?;
+    Ok(buffer)
+}
+
+/// First letters of the first and last word, "?" for names without any letters
+fn initials(name: &str) -> String {
+    let mut words = name
+        .split_whitespace()
+        .filter_map(|word| word.chars().find(|c| c.is_alphanumeric()));
+    let initials: String = match (words.next(), words.next_back()) {
+        (Some(first), Some(last)) => [first, last].iter().collect(),
+        (Some(first), None) => first.to_string(),
+        _ => "?".to_string(),
+    };
+    initials.to_uppercase()
+}
+
+/// A color dark enough for white text, with the hue taken from the hash
+fn color(hash: u64) -> Rgb<u8> {
+    let hue = (hash % 360) as f32;
+    let (saturation, lightness) = (0.55, 0.45);
+
+    let chroma = (1.0 - (2.0 * lightness - 1.0f32).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |value: f32| ((value + m) * 255.0).round() as u8;
+    Rgb([channel(r), channel(g), channel(b)])
+}
+
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
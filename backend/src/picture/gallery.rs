@@ -0,0 +1,38 @@
+//! The ordered picture galleries of products. The first image by position is the
+//! primary one, `products.picture` mirrors its key, so every change to a gallery
+//! has to end with `sync_primary` in the same transaction.
+
+use sqlx::PgConnection;
+
+use crate::db::PrimaryKey;
+
+/// Locks the product row until the end of the transaction, false if there is no such product
+pub async fn lock_product(conn: &mut PgConnection, product_id: PrimaryKey) -> sqlx::Result<bool> {
+    let product = sqlx::query!(
+        "SELECT id FROM products WHERE id = $1 FOR UPDATE",
+        product_id
+    )
+    .fetch_optional(conn)
+    .await?;
+    Ok(product.is_some())
+}
+
+/// Points `products.picture` to the current primary image, NULL for empty galleries
+pub async fn sync_primary(conn: &mut PgConnection, product_id: PrimaryKey) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE products
+        SET picture = (
+            SELECT picture FROM product_images
+            WHERE product_id = $1
+            ORDER BY position
+            LIMIT 1
+        )
+        WHERE id = $1
+        "#,
+        product_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,166 @@
+//! Finds pictures the database and the storage disagree about: files no account
+//! or product points to anymore, and picture keys whose object is missing.
+
+use std::collections::HashSet;
+
+use chrono::{Duration, Utc};
+use color_eyre::eyre::Result;
+use once_cell::sync::Lazy;
+use poem_openapi::{Enum, Object};
+use sqlx::{Pool, Postgres};
+use tracing::{info, warn};
+
+use crate::{config::SETTINGS, events, storage::storage};
+
+use super::{
+    full_account_picture_key, full_product_picture_key, gallery, thumbnail_key, THUMBNAIL_SIZES,
+};
+
+/// Objects younger than this are never orphans, their upload might still be running
+const MIN_ORPHAN_AGE_HOURS: i64 = 1;
+
+static INTERVAL_HOURS: Lazy<i64> =
+    Lazy::new(|| SETTINGS.get_int("pictures.gc_interval_hours").unwrap());
+static REMOVE: Lazy<bool> = Lazy::new(|| SETTINGS.get_bool("pictures.gc_remove").unwrap());
+
+#[derive(Enum, Clone, Copy)]
+#[oai(rename_all = "lowercase")]
+pub enum PictureOwner {
+    Account,
+    Product,
+}
+
+#[derive(Object)]
+pub struct DanglingPicture {
+    pub owner: PictureOwner,
+    pub id: String,
+    /// The partial key stored in the database
+    pub key: String,
+}
+
+#[derive(Object)]
+pub struct GarbageReport {
+    /// Full keys of objects nothing points to
+    pub orphaned_objects: Vec<String>,
+    /// Pictures in the database whose object is missing
+    pub dangling_pictures: Vec<DanglingPicture>,
+    /// Whether the orphans were deleted and the dangling pictures set to null,
+    /// or removed from the gallery for products
+    pub removed: bool,
+}
+
+/// Compares the storage with the database, with `remove` the differences are cleaned up
+pub async fn collect_garbage(db: &Pool<Postgres>, remove: bool) -> Result<GarbageReport> {
+    let accounts =
+        sqlx::query!(r#"SELECT id, picture AS "picture!" FROM accounts WHERE picture IS NOT NULL"#)
+            .fetch_all(db)
+            .await?
+            .into_iter()
+            .map(|row| (PictureOwner::Account, row.id, row.picture));
+    // products.picture only mirrors the primary image, the galleries hold every key
+    let products = sqlx::query!("SELECT product_id, picture FROM product_images")
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(|row| {
+            (
+                PictureOwner::Product,
+                row.product_id.to_string(),
+                row.picture,
+            )
+        });
+
+    let mut objects = storage().list_files(&full_account_picture_key("")).await?;
+    objects.extend(storage().list_files(&full_product_picture_key("")).await?);
+    let stored: HashSet<&str> = objects.iter().map(|object| object.key.as_str()).collect();
+
+    let mut referenced = HashSet::new();
+    let mut dangling_pictures = Vec::new();
+    for (owner, id, key) in accounts.chain(products) {
+        let full_key = match owner {
+            PictureOwner::Account => full_account_picture_key,
+            PictureOwner::Product => full_product_picture_key,
+        };
+        if !stored.contains(full_key(&key).as_str()) {
+            dangling_pictures.push(DanglingPicture { owner, id, key });
+            continue;
+        }
+        referenced.insert(full_key(&key));
+        for &size in THUMBNAIL_SIZES {
+            referenced.insert(full_key(&thumbnail_key(&key, size)));
+        }
+    }
+
+    let min_age = Utc::now() - Duration::hours(MIN_ORPHAN_AGE_HOURS);
+    let orphaned_objects: Vec<String> = objects
+        .iter()
+        .filter(|object| !referenced.contains(&object.key))
+        .filter(|object| object.last_modified.is_some_and(|time| time < min_age))
+        .map(|object| object.key.clone())
+        .collect();
+
+    if remove {
+        for key in &orphaned_objects {
+            storage().delete_file(key).await?;
+        }
+        for picture in &dangling_pictures {
+            // only if nobody uploaded a new picture in the meantime
+            match picture.owner {
+                PictureOwner::Account => {
+                    sqlx::query!(
+                        "UPDATE accounts SET picture = NULL WHERE id = $1 AND picture = $2",
+                        picture.id,
+                        picture.key
+                    )
+                    .execute(db)
+                    .await?;
+                }
+                PictureOwner::Product => {
+                    let product_id = picture.id.parse::<i64>()?;
+                    let mut tx = db.begin().await?;
+                    gallery::lock_product(&mut tx, product_id).await?;
+                    sqlx::query!(
+                        "DELETE FROM product_images WHERE product_id = $1 AND picture = $2",
+                        product_id,
+                        picture.key
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                    gallery::sync_primary(&mut tx, product_id).await?;
+                    tx.commit().await?;
+                    events::publish_product_update(db, product_id).await;
+                }
+            }
+        }
+    }
+
+    info!(
+        "Picture garbage collection found {} orphaned objects and {} dangling pictures{}",
+        orphaned_objects.len(),
+        dangling_pictures.len(),
+        if remove { ", removed them" } else { "" }
+    );
+
+    Ok(GarbageReport {
+        orphaned_objects,
+        dangling_pictures,
+        removed: remove,
+    })
+}
+
+/// Runs the garbage collection every `pictures.gc_interval_hours`, never if that is 0
+pub async fn run_periodically(db: Pool<Postgres>) {
+    if *INTERVAL_HOURS <= 0 {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+        *INTERVAL_HOURS as u64 * 3600,
+    ));
+    loop {
+        interval.tick().await;
+        if let Err(err) = collect_garbage(&db, *REMOVE).await {
+            warn!("Picture garbage collection failed: {err}");
+        }
+    }
+}
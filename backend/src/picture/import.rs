@@ -0,0 +1,307 @@
+//! Imports account pictures from elsewhere, either the picture claim of the login
+//! token or the Gravatar of the email address. The pictures go through the same
+//! processing as uploads. Picture claims can point anywhere, so downloads only go
+//! to public addresses over https.
+
+use std::{
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use color_eyre::eyre::{bail, ensure, eyre};
+use futures_util::StreamExt;
+use poem::{error::InternalServerError, http::StatusCode, Result};
+use reqwest::{header::LOCATION, redirect::Policy, Client, Url};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use tracing::{debug, info, warn};
+
+use crate::config::SETTINGS;
+
+use super::{delete_picture, full_account_picture_key, store_picture};
+
+#[derive(Clone, Copy, Debug)]
+enum ImportSource {
+    Claim,
+    Gravatar,
+}
+
+struct ImportConfig {
+    sources: Vec<ImportSource>,
+    timeout: Duration,
+    /// Downloads are held to the same limit as uploads
+    max_download_size: usize,
+}
+
+static CONFIG: OnceLock<ImportConfig> = OnceLock::new();
+
+/// Loads the `pictures.import` section of the config, see [`super::init`]
+pub fn init() -> color_eyre::Result<()> {
+    let sources = SETTINGS
+        .get::<Vec<String>>("pictures.import.sources")?
+        .iter()
+        .map(|source| match source.as_str() {
+            "claim" => Ok(ImportSource::Claim),
+            "gravatar" => Ok(ImportSource::Gravatar),
+            source => Err(eyre!(
+                "Unknown picture import source {source}, expected claim or gravatar"
+            )),
+        })
+        .collect::<color_eyre::Result<_>>()?;
+    let timeout_seconds = SETTINGS.get_int("pictures.import.timeout_seconds")?;
+    ensure!(
+        timeout_seconds >= 0,
+        "pictures.import.timeout_seconds can't be negative, got {timeout_seconds}"
+    );
+    let max_size = SETTINGS.get_int("uploads.max_size")?;
+    let max_download_size = usize::try_from(max_size)
+        .map_err(|_| eyre!("uploads.max_size can't be negative, got {max_size}"))?;
+
+    let config = ImportConfig {
+        sources,
+        timeout: Duration::from_secs(timeout_seconds as u64),
+        max_download_size,
+    };
+
+    if CONFIG.set(config).is_err() {
+        panic!("Picture import config initialized twice");
+    }
+    Ok(())
+}
+
+fn config() -> &'static ImportConfig {
+    CONFIG
+        .get()
+        .expect("Picture import config isn't initialized")
+}
+
+/// Redirects are followed by hand, so every hop goes through the same checks
+const MAX_REDIRECTS: usize = 3;
+
+/// Accounts the backfill imports pictures for at the same time
+const BACKFILL_CONCURRENCY: usize = 4;
+
+static BACKFILL_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Tries the sources from `pictures.import.sources` in order and stores the first
+/// picture one of them has. Accounts which got a picture in the meantime keep it.
+/// Returns whether a picture was imported.
+pub async fn import_account_picture(
+    db: &Pool<Postgres>,
+    account_id: &str,
+    claim: Option<&str>,
+    email: &str,
+) -> Result<bool> {
+    for &source in config().sources.iter() {
+        let url = match source {
+            ImportSource::Claim => match claim {
+                Some(url) => url.to_string(),
+                None => continue,
+            },
+            ImportSource::Gravatar => gravatar_url(email),
+        };
+
+        let Some(bytes) = download(&url).await else {
+            continue;
+        };
+
+        let picture = Cursor::new(bytes);
+        let part_key = match store_picture(picture, account_id, full_account_picture_key).await {
+            Ok(part_key) => part_key,
+            Err(err) if err.status() == StatusCode::BAD_REQUEST => {
+                warn!("{source:?} picture of account {account_id} is no valid picture: {err}");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        let updated = sqlx::query!(
+            "UPDATE accounts SET picture = $2 WHERE id = $1 AND picture IS NULL",
+            account_id,
+            part_key
+        )
+        .execute(db)
+        .await
+        .map_err(InternalServerError)?
+        .rows_affected();
+
+        if updated == 0 {
+            delete_picture(&part_key, full_account_picture_key).await;
+            return Ok(false);
+        }
+
+        info!("Imported {source:?} picture of account {account_id}");
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+/// Starts importing pictures for every account without one in the background,
+/// failures are logged per account. Only Gravatar can be used here, the claims of
+/// other accounts aren't known. Returns for how many accounts an import is tried.
+pub async fn backfill_account_pictures(db: &Pool<Postgres>) -> Result<usize> {
+    if BACKFILL_RUNNING.swap(true, Ordering::AcqRel) {
+        return Err(poem::Error::from_string(
+            "A picture import is running already",
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    let accounts =
+        sqlx::query!("SELECT id, email FROM accounts WHERE picture IS NULL AND deleted_at IS NULL")
+            .fetch_all(db)
+            .await
+            .map_err(|err| {
+                BACKFILL_RUNNING.store(false, Ordering::Release);
+                InternalServerError(err)
+            })?;
+
+    let count = accounts.len();
+    info!("Importing pictures for {count} accounts");
+
+    let db = db.clone();
+    tokio::spawn(async move {
+        let imported = AtomicUsize::new(0);
+        futures_util::stream::iter(accounts)
+            .for_each_concurrent(BACKFILL_CONCURRENCY, |account| {
+                let (db, imported) = (&db, &imported);
+                async move {
+                    match import_account_picture(db, &account.id, None, &account.email).await {
+                        Ok(true) => {
+                            imported.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Ok(false) => {}
+                        Err(err) => {
+                            warn!(
+                                "Importing the picture of account {} failed: {err}",
+                                account.id
+                            )
+                        }
+                    }
+                }
+            })
+            .await;
+
+        info!(
+            "Imported pictures for {} of {count} accounts",
+            imported.into_inner()
+        );
+        BACKFILL_RUNNING.store(false, Ordering::Release);
+    });
+
+    Ok(count)
+}
+
+/// `None` if there is nothing to download or the download failed, which is logged
+async fn download(url: &str) -> Option<Vec<u8>> {
+    let result = async {
+        let mut url = Url::parse(url)?;
+        for _ in 0..=MAX_REDIRECTS {
+            let response = public_client(&url).await?.get(url.clone()).send().await?;
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .ok_or_else(|| eyre!("redirect without a location"))?
+                    .to_str()?;
+                url = url.join(location)?;
+                continue;
+            }
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                debug!("No picture at {url}");
+                return Ok(None);
+            }
+            let mut response = response.error_for_status()?;
+
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                if body.len() + chunk.len() > config().max_download_size {
+                    warn!(
+                        "Picture at {url} is larger than {} bytes",
+                        config().max_download_size
+                    );
+                    return Ok(None);
+                }
+                body.extend_from_slice(&chunk);
+            }
+            return Ok(Some(body));
+        }
+        bail!("more than {MAX_REDIRECTS} redirects")
+    }
+    .await;
+
+    result.unwrap_or_else(|err: color_eyre::Report| {
+        warn!("Couldn't download picture from {url}: {err}");
+        None
+    })
+}
+
+/// A client for `url` which only connects to the addresses checked here, so the host
+/// can't resolve to a public address for the check and an internal one afterwards
+async fn public_client(url: &Url) -> color_eyre::Result<Client> {
+    ensure!(url.scheme() == "https", "only https urls are supported");
+    let host = url.host_str().ok_or_else(|| eyre!("the url has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    // IPv6 hosts come in brackets, which the lookup doesn't take
+    let addrs: Vec<SocketAddr> =
+        tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port))
+            .await?
+            .collect();
+    ensure!(!addrs.is_empty(), "{host} doesn't resolve to any address");
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        bail!("{host} resolves to the non-public address {}", addr.ip());
+    }
+
+    Ok(Client::builder()
+        .timeout(config().timeout)
+        .redirect(Policy::none())
+        .resolve_to_addrs(host, &addrs)
+        .build()?)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    // 0.0.0.0/8 is "this network" and 100.64.0.0/10 carrier-grade NAT
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        || (a == 100 && (64..128).contains(&b)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    // fc00::/7 are unique local addresses and fe80::/10 link-local ones
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80)
+}
+
+/// `d=404` makes Gravatar answer 404 instead of its default picture
+fn gravatar_url(email: &str) -> String {
+    let hash = Sha256::digest(email.trim().to_lowercase().as_bytes());
+    format!("https://gravatar.com/avatar/{hash:x}?s=1024&d=404")
+}
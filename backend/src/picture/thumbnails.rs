@@ -0,0 +1,91 @@
+//! Pictures stored before there were thumbnails only exist in their full size, while
+//! their urls point to the thumbnails like for every other picture. The backfill
+//! scales them down once, so those urls work for them too.
+
+use std::{collections::HashSet, io::Cursor};
+
+use color_eyre::eyre::Result;
+use sqlx::{Pool, Postgres};
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+
+use crate::storage::storage;
+
+use super::{
+    full_account_picture_key, full_product_picture_key, process_thumbnails, thumbnail_key,
+    THUMBNAIL_SIZES,
+};
+
+/// Creates the missing thumbnails of all pictures, meant to run in the background
+/// at startup. Pictures that fail are logged and skipped.
+pub async fn backfill(db: Pool<Postgres>) {
+    match backfill_missing(&db).await {
+        Ok(0) => {}
+        Ok(count) => info!("Created missing thumbnails for {count} pictures"),
+        Err(err) => warn!("Couldn't look for pictures without thumbnails: {err}"),
+    }
+}
+
+async fn backfill_missing(db: &Pool<Postgres>) -> Result<usize> {
+    let accounts = sqlx::query_scalar!(
+        r#"SELECT picture AS "picture!" FROM accounts WHERE picture IS NOT NULL"#
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|key| (key, full_account_picture_key as fn(&str) -> String));
+    let products = sqlx::query_scalar!("SELECT picture FROM product_images")
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(|key| (key, full_product_picture_key as fn(&str) -> String));
+
+    let mut objects = storage().list_files(&full_account_picture_key("")).await?;
+    objects.extend(storage().list_files(&full_product_picture_key("")).await?);
+    let stored: HashSet<String> = objects.into_iter().map(|object| object.key).collect();
+
+    let mut count = 0;
+    for (key, full_key) in accounts.chain(products) {
+        // pictures missing entirely are left to the garbage collection
+        let complete = THUMBNAIL_SIZES
+            .iter()
+            .all(|&size| stored.contains(&full_key(&thumbnail_key(&key, size))));
+        if complete || !stored.contains(&full_key(&key)) {
+            continue;
+        }
+
+        match create_thumbnails(&key, full_key).await {
+            Ok(()) => count += 1,
+            Err(err) => warn!("Couldn't create thumbnails for picture {key}: {err}"),
+        }
+    }
+
+    Ok(count)
+}
+
+/// Thumbnails are encoded in the format the extension of the key names, since
+/// some storages derive the content type from it
+async fn create_thumbnails(part_key: &str, full_key: fn(&str) -> String) -> Result<()> {
+    let Some(mut file) = storage().get_file(&full_key(part_key)).await? else {
+        return Ok(());
+    };
+    let mut bytes = Vec::new();
+    file.body.read_to_end(&mut bytes).await?;
+
+    let png = part_key.ends_with(".png");
+    let thumbnails =
+        tokio::task::spawn_blocking(move || process_thumbnails(Cursor::new(bytes), png)).await??;
+
+    let content_type = if png { "image/png" } else { "image/jpeg" };
+    for (size, thumbnail) in thumbnails {
+        storage()
+            .upload_file(
+                &full_key(&thumbnail_key(part_key, size)),
+                thumbnail,
+                content_type,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
@@ -1 +1,2 @@
+pub mod exportapi;
 pub mod pictureapi;
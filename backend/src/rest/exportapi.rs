@@ -0,0 +1,442 @@
+use std::io;
+
+use chrono::{Days, NaiveDate, NaiveDateTime};
+use futures_util::{stream, Stream, TryStreamExt};
+use once_cell::sync::Lazy;
+use poem::{error::InternalServerError, http::StatusCode, web::Data, Body, Result};
+use poem_openapi::{param::Query, payload::Binary, ApiResponse, Enum, OpenApi};
+use rust_xlsxwriter::{Format, Workbook};
+use sqlx::{Pool, Postgres};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{
+    auth::JwtBearerAuth,
+    config::SETTINGS,
+    db::{PrimaryKey, ProductType},
+};
+
+mod datev;
+mod ledger;
+
+use ledger::LedgerFormat;
+
+/// Loads the export settings, see [`datev::init`]
+pub fn init() -> color_eyre::Result<()> {
+    datev::init()
+}
+
+/// Exports are in this timezone, the same one the statistics use
+static TIMEZONE: Lazy<String> = Lazy::new(|| SETTINGS.get_string("statistics.timezone").unwrap());
+
+/// The column layout of the accounting export, finance builds on it, so only ever append
+const ACCOUNTING_COLUMNS: &[&str] = &[
+    "date",
+    "type",
+    "account_id",
+    "account_name",
+    "product_id",
+    "product_name",
+    "deposit_type",
+    "quantity",
+    "price",
+    "deposit",
+    "balance_change",
+];
+
+#[derive(Enum, Clone, Copy)]
+#[oai(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Xlsx,
+}
+
+#[derive(ApiResponse)]
+pub enum ExportResponse {
+    #[oai(status = 200, content_type = "text/csv; charset=utf-8")]
+    Csv(Binary<Body>, #[oai(header = "Content-Disposition")] String),
+    #[oai(
+        status = 200,
+        content_type = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+    )]
+    Xlsx(
+        Binary<Vec<u8>>,
+        #[oai(header = "Content-Disposition")] String,
+    ),
+    #[oai(status = 200, content_type = "text/csv; charset=windows-1252")]
+    Datev(
+        Binary<Vec<u8>>,
+        #[oai(header = "Content-Disposition")] String,
+    ),
+    #[oai(status = 200, content_type = "text/plain; charset=utf-8")]
+    Ledger(
+        Binary<Vec<u8>>,
+        #[oai(header = "Content-Disposition")] String,
+    ),
+}
+
+/// A single booking, amounts are in cents. Refunds and deposit returns have
+/// negative amounts, so summing up a column gives the net amount.
+struct AccountingEntry {
+    /// Id of the purchase or the deposit return, a refund has the id of its purchase
+    id: PrimaryKey,
+    date: NaiveDateTime,
+    kind: String,
+    account_id: String,
+    account_name: String,
+    product_id: Option<PrimaryKey>,
+    product_name: Option<String>,
+    product_type: Option<ProductType>,
+    deposit_type: Option<String>,
+    quantity: i32,
+    price: i64,
+    deposit: i64,
+}
+
+impl AccountingEntry {
+    /// What happened to the balance of the account, the opposite of what we earned
+    fn balance_change(&self) -> i64 {
+        -(self.price + self.deposit)
+    }
+}
+
+#[derive(Default)]
+struct AccountingTotals {
+    price: i64,
+    deposit: i64,
+    balance_change: i64,
+}
+
+impl AccountingTotals {
+    fn add(&mut self, entry: &AccountingEntry) {
+        self.price += entry.price;
+        self.deposit += entry.deposit;
+        self.balance_change += entry.balance_change();
+    }
+}
+
+pub struct ExportApi;
+
+#[OpenApi(prefix_path = "/export")]
+impl ExportApi {
+    /// Purchases, refunds and deposit returns between `from` and `to`, both inclusive.
+    /// Refunds and deposit returns are negative, the last row holds the totals.
+    #[oai(path = "/accounting", method = "get")]
+    pub async fn accounting_export(
+        &self,
+        Query(from): Query<NaiveDate>,
+        Query(to): Query<NaiveDate>,
+        Query(format): Query<ExportFormat>,
+        Data(db): Data<&Pool<Postgres>>,
+        JwtBearerAuth(user_claims): JwtBearerAuth,
+    ) -> Result<ExportResponse> {
+        if !user_claims.is_admin() {
+            return Err(poem::Error::from_status(StatusCode::FORBIDDEN));
+        }
+        if from > to {
+            return Err(poem::Error::from_string(
+                "from needs to be before to",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+
+        info!("Exporting accounting report from {from} to {to}");
+
+        let start = from.and_hms_opt(0, 0, 0).unwrap();
+        let end = (to + Days::new(1)).and_hms_opt(0, 0, 0).unwrap();
+
+        match format {
+            ExportFormat::Csv => Ok(ExportResponse::Csv(
+                Binary(accounting_csv(db.clone(), start, end)),
+                content_disposition(from, to, "csv"),
+            )),
+            ExportFormat::Xlsx => Ok(ExportResponse::Xlsx(
+                Binary(accounting_xlsx(db, start, end).await?),
+                content_disposition(from, to, "xlsx"),
+            )),
+        }
+    }
+
+    /// Daily revenue per product type, deposits and deposit returns between `from` and
+    /// `to`, both inclusive, as a DATEV Buchungsstapel. The accounts and VAT keys are
+    /// configured in the `datev` section of the config.
+    #[oai(path = "/datev", method = "get")]
+    pub async fn datev_export(
+        &self,
+        Query(from): Query<NaiveDate>,
+        Query(to): Query<NaiveDate>,
+        Data(db): Data<&Pool<Postgres>>,
+        JwtBearerAuth(user_claims): JwtBearerAuth,
+    ) -> Result<ExportResponse> {
+        if !user_claims.is_admin() {
+            return Err(poem::Error::from_status(StatusCode::FORBIDDEN));
+        }
+        if from > to {
+            return Err(poem::Error::from_string(
+                "from needs to be before to",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+
+        info!("Exporting DATEV bookings from {from} to {to}");
+
+        Ok(ExportResponse::Datev(
+            Binary(datev::export(db, from, to).await?),
+            format!("attachment; filename=\"EXTF_Buchungsstapel_{from}_{to}.csv\""),
+        ))
+    }
+
+    /// Every purchase, refund and deposit return as a balanced transaction for hledger
+    /// or beancount, optionally limited to the days from `from` to `to`, both inclusive
+    #[oai(path = "/ledger", method = "get")]
+    pub async fn ledger_export(
+        &self,
+        Query(format): Query<LedgerFormat>,
+        Query(from): Query<Option<NaiveDate>>,
+        Query(to): Query<Option<NaiveDate>>,
+        Data(db): Data<&Pool<Postgres>>,
+        JwtBearerAuth(user_claims): JwtBearerAuth,
+    ) -> Result<ExportResponse> {
+        if !user_claims.is_admin() {
+            return Err(poem::Error::from_status(StatusCode::FORBIDDEN));
+        }
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err(poem::Error::from_string(
+                    "from needs to be before to",
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+        }
+
+        info!("Exporting ledger from {from:?} to {to:?}");
+
+        let start = from.map(|from| from.and_hms_opt(0, 0, 0).unwrap());
+        let end = to.map(|to| (to + Days::new(1)).and_hms_opt(0, 0, 0).unwrap());
+        let extension = match format {
+            LedgerFormat::Hledger => "journal",
+            LedgerFormat::Beancount => "beancount",
+        };
+        let period = match (from, to) {
+            (None, None) => String::new(),
+            (from, to) => format!(
+                "_{}_{}",
+                from.map(|from| from.to_string()).unwrap_or_default(),
+                to.map(|to| to.to_string()).unwrap_or_default()
+            ),
+        };
+
+        Ok(ExportResponse::Ledger(
+            Binary(ledger::export(db, format, start, end).await?),
+            format!("attachment; filename=\"ruscalimat{period}.{extension}\""),
+        ))
+    }
+}
+
+fn content_disposition(from: NaiveDate, to: NaiveDate, extension: &str) -> String {
+    format!("attachment; filename=\"accounting_{from}_{to}.{extension}\"")
+}
+
+/// Streams the entries as CSV while they are read from the database. A failure
+/// ends the body with an error, so the download breaks off instead of looking complete.
+fn accounting_csv(db: Pool<Postgres>, start: NaiveDateTime, end: NaiveDateTime) -> Body {
+    let (sender, receiver) = mpsc::channel::<io::Result<Vec<u8>>>(16);
+
+    tokio::spawn(async move {
+        let result: color_eyre::Result<()> = async {
+            sender.send(Ok(csv_row(ACCOUNTING_COLUMNS)?)).await?;
+
+            let mut totals = AccountingTotals::default();
+            let mut entries = accounting_entries(&db, Some(start), Some(end));
+            while let Some(entry) = entries.try_next().await? {
+                totals.add(&entry);
+                sender.send(Ok(csv_row(&entry_fields(&entry))?)).await?;
+            }
+
+            sender.send(Ok(csv_row(&totals_fields(&totals))?)).await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(err) = result {
+            warn!("Accounting CSV export failed: {err}");
+            let err = io::Error::other(err.to_string());
+            let _ = sender.send(Err(err)).await;
+        }
+    });
+
+    Body::from_bytes_stream(stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|row| (row, receiver))
+    }))
+}
+
+fn csv_row<T: AsRef<[u8]>>(fields: &[T]) -> csv::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(fields)?;
+    writer
+        .into_inner()
+        .map_err(|err| csv::Error::from(err.into_error()))
+}
+
+fn entry_fields(entry: &AccountingEntry) -> Vec<String> {
+    vec![
+        entry.date.format("%Y-%m-%d %H:%M:%S").to_string(),
+        entry.kind.clone(),
+        entry.account_id.clone(),
+        entry.account_name.clone(),
+        entry
+            .product_id
+            .map(|id| id.to_string())
+            .unwrap_or_default(),
+        entry.product_name.clone().unwrap_or_default(),
+        entry.deposit_type.clone().unwrap_or_default(),
+        entry.quantity.to_string(),
+        format_cents(entry.price),
+        format_cents(entry.deposit),
+        format_cents(entry.balance_change()),
+    ]
+}
+
+fn totals_fields(totals: &AccountingTotals) -> Vec<String> {
+    let mut fields = vec![String::new(); ACCOUNTING_COLUMNS.len()];
+    fields[1] = "total".to_string();
+    fields[8] = format_cents(totals.price);
+    fields[9] = format_cents(totals.deposit);
+    fields[10] = format_cents(totals.balance_change);
+    fields
+}
+
+/// Formats cents as a decimal number with two places, e.g. -150 as -1.50
+fn format_cents(cents: i64) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    let cents = cents.unsigned_abs();
+    format!("{sign}{}.{:02}", cents / 100, cents % 100)
+}
+
+async fn accounting_xlsx(
+    db: &Pool<Postgres>,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> Result<Vec<u8>> {
+    let entries: Vec<AccountingEntry> = accounting_entries(db, Some(start), Some(end))
+        .try_collect()
+        .await
+        .map_err(InternalServerError)?;
+
+    let bold = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+    let money = Format::new().set_num_format("0.00");
+    let total_money = Format::new().set_num_format("0.00").set_bold();
+
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    sheet.set_name("Accounting").map_err(InternalServerError)?;
+
+    for (col, name) in ACCOUNTING_COLUMNS.iter().enumerate() {
+        sheet
+            .write_string_with_format(0, col as u16, *name, &bold)
+            .map_err(InternalServerError)?;
+    }
+
+    let mut totals = AccountingTotals::default();
+    let mut row = 0;
+    for entry in &entries {
+        row += 1;
+        totals.add(entry);
+
+        sheet
+            .write_datetime_with_format(row, 0, entry.date, &date)
+            .and_then(|sheet| sheet.write_string(row, 1, &entry.kind))
+            .and_then(|sheet| sheet.write_string(row, 2, &entry.account_id))
+            .and_then(|sheet| sheet.write_string(row, 3, &entry.account_name))
+            .and_then(|sheet| sheet.write_number(row, 7, entry.quantity))
+            .and_then(|sheet| sheet.write_number_with_format(row, 8, cents(entry.price), &money))
+            .and_then(|sheet| sheet.write_number_with_format(row, 9, cents(entry.deposit), &money))
+            .and_then(|sheet| {
+                sheet.write_number_with_format(row, 10, cents(entry.balance_change()), &money)
+            })
+            .map_err(InternalServerError)?;
+
+        if let Some(product_id) = entry.product_id {
+            sheet
+                .write_number(row, 4, product_id as f64)
+                .map_err(InternalServerError)?;
+        }
+        if let Some(product_name) = &entry.product_name {
+            sheet
+                .write_string(row, 5, product_name)
+                .map_err(InternalServerError)?;
+        }
+        if let Some(deposit_type) = &entry.deposit_type {
+            sheet
+                .write_string(row, 6, deposit_type)
+                .map_err(InternalServerError)?;
+        }
+    }
+
+    row += 1;
+    sheet
+        .write_string_with_format(row, 1, "total", &bold)
+        .and_then(|sheet| sheet.write_number_with_format(row, 8, cents(totals.price), &total_money))
+        .and_then(|sheet| {
+            sheet.write_number_with_format(row, 9, cents(totals.deposit), &total_money)
+        })
+        .and_then(|sheet| {
+            sheet.write_number_with_format(row, 10, cents(totals.balance_change), &total_money)
+        })
+        .map_err(InternalServerError)?;
+
+    sheet.set_column_width(0, 20).map_err(InternalServerError)?;
+    sheet.set_freeze_panes(1, 0).map_err(InternalServerError)?;
+
+    workbook.save_to_buffer().map_err(InternalServerError)
+}
+
+fn cents(cents: i64) -> f64 {
+    cents as f64 / 100.0
+}
+
+/// All bookings in the time range, oldest first. Times are converted from the
+/// wall clock time of the database to the export timezone.
+fn accounting_entries<'a>(
+    db: &'a Pool<Postgres>,
+    start: Option<NaiveDateTime>,
+    end: Option<NaiveDateTime>,
+) -> impl Stream<Item = sqlx::Result<AccountingEntry>> + Send + 'a {
+    sqlx::query_as!(
+        AccountingEntry,
+        r#"
+        SELECT entries.id AS "id!", entries.date AS "date!", entries.kind AS "kind!",
+        accounts.id AS account_id, accounts.name AS account_name,
+        entries.product_id, products.name AS "product_name?",
+        products.product_type AS "product_type?: ProductType",
+        deposit_types.name AS "deposit_type?",
+        entries.quantity AS "quantity!", entries.price AS "price!", entries.deposit AS "deposit!"
+        FROM (
+            SELECT id, created_at::timestamptz AT TIME ZONE $1 AS date, 'purchase' AS kind,
+            account_id, product_id, deposit_type_id, quantity, paid_price AS price, deposit
+            FROM purchases
+            WHERE created_at IS NOT NULL
+            UNION ALL
+            SELECT id, refunded_at::timestamptz AT TIME ZONE $1, 'refund',
+            account_id, product_id, deposit_type_id, -quantity, -paid_price, -deposit
+            FROM purchases
+            WHERE refunded_at IS NOT NULL AND created_at IS NOT NULL
+            UNION ALL
+            SELECT id, returned_at::timestamptz AT TIME ZONE $1, 'deposit_return',
+            account_id, NULL, deposit_type_id, quantity, 0, -amount
+            FROM deposit_returns
+        ) AS entries
+        JOIN accounts ON accounts.id = entries.account_id
+        LEFT JOIN products ON products.id = entries.product_id
+        LEFT JOIN deposit_types ON deposit_types.id = entries.deposit_type_id
+        WHERE ($2::TIMESTAMP IS NULL OR entries.date >= $2)
+        AND ($3::TIMESTAMP IS NULL OR entries.date < $3)
+        ORDER BY entries.date, entries.kind, entries.id
+        "#,
+        TIMEZONE.as_str(),
+        start,
+        end
+    )
+    .fetch(db)
+}
@@ -0,0 +1,282 @@
+//! Bookkeeping export in the DATEV format (EXTF Buchungsstapel), which tax advisors
+//! in Germany import. Revenue and deposits are booked as daily totals against the
+//! account holding the prepaid balances of our customers.
+
+use std::{str::FromStr, sync::OnceLock};
+
+use chrono::{Datelike, NaiveDate, Utc};
+use chrono_tz::Tz;
+use color_eyre::eyre::ensure;
+use poem::{error::InternalServerError, http::StatusCode, Result};
+use sqlx::{Pool, Postgres};
+
+use crate::{config::SETTINGS, db::ProductType};
+
+use super::{format_cents, TIMEZONE};
+
+struct DatevConfig {
+    consultant_number: i64,
+    client_number: i64,
+    fiscal_year_start_month: u32,
+    account_length: i64,
+    balance_account: i64,
+    deposit_account: i64,
+    deposit_vat_key: String,
+    hot_drink: RevenueAccount,
+    cold_drink: RevenueAccount,
+}
+
+struct RevenueAccount {
+    account: i64,
+    vat_key: String,
+}
+
+static CONFIG: OnceLock<DatevConfig> = OnceLock::new();
+
+/// Loads the `datev` section of the config, called at startup so a broken config
+/// fails there instead of in the middle of an export
+pub fn init() -> color_eyre::Result<()> {
+    let fiscal_year_start_month = SETTINGS.get_int("datev.fiscal_year_start_month")?;
+    ensure!(
+        (1..=12).contains(&fiscal_year_start_month),
+        "datev.fiscal_year_start_month needs to be between 1 and 12, got {fiscal_year_start_month}"
+    );
+
+    let config = DatevConfig {
+        consultant_number: SETTINGS.get_int("datev.consultant_number")?,
+        client_number: SETTINGS.get_int("datev.client_number")?,
+        fiscal_year_start_month: fiscal_year_start_month as u32,
+        account_length: SETTINGS.get_int("datev.account_length")?,
+        balance_account: SETTINGS.get_int("datev.balance_account")?,
+        deposit_account: SETTINGS.get_int("datev.deposit_account")?,
+        deposit_vat_key: SETTINGS.get_string("datev.vat_keys.deposit")?,
+        hot_drink: RevenueAccount {
+            account: SETTINGS.get_int("datev.revenue_accounts.hotdrink")?,
+            vat_key: SETTINGS.get_string("datev.vat_keys.hotdrink")?,
+        },
+        cold_drink: RevenueAccount {
+            account: SETTINGS.get_int("datev.revenue_accounts.colddrink")?,
+            vat_key: SETTINGS.get_string("datev.vat_keys.colddrink")?,
+        },
+    };
+
+    if CONFIG.set(config).is_err() {
+        panic!("DATEV config initialized twice");
+    }
+    Ok(())
+}
+
+fn config() -> &'static DatevConfig {
+    CONFIG.get().expect("DATEV config isn't initialized")
+}
+
+const COLUMNS: &[&str] = &[
+    "Umsatz (ohne Soll/Haben-Kz)",
+    "Soll/Haben-Kennzeichen",
+    "WKZ Umsatz",
+    "Kurs",
+    "Basis-Umsatz",
+    "WKZ Basis-Umsatz",
+    "Konto",
+    "Gegenkonto (ohne BU-Schlüssel)",
+    "BU-Schlüssel",
+    "Belegdatum",
+    "Belegfeld 1",
+    "Belegfeld 2",
+    "Skonto",
+    "Buchungstext",
+];
+
+struct Booking {
+    /// In cents, positive amounts debit the balance account
+    amount: i64,
+    counter_account: i64,
+    vat_key: String,
+    date: NaiveDate,
+    document_number: String,
+    text: &'static str,
+}
+
+/// Builds the export for the days from `from` to `to`, both inclusive,
+/// which need to be in the same fiscal year
+pub async fn export(db: &Pool<Postgres>, from: NaiveDate, to: NaiveDate) -> Result<Vec<u8>> {
+    let year_start = fiscal_year_start(from);
+    if year_start != fiscal_year_start(to) {
+        return Err(poem::Error::from_string(
+            "The period can't span more than one fiscal year",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let bookings = bookings(db, from, to).await?;
+
+    let tz = Tz::from_str(&TIMEZONE).map_err(|err| {
+        poem::Error::from_string(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+    })?;
+    let created_at = Utc::now().with_timezone(&tz);
+
+    let header = [
+        quoted("EXTF"),
+        "700".to_string(),
+        "21".to_string(),
+        quoted("Buchungsstapel"),
+        "12".to_string(),
+        created_at.format("%Y%m%d%H%M%S%3f").to_string(),
+        String::new(),
+        quoted("RE"),
+        quoted(""),
+        quoted(""),
+        config().consultant_number.to_string(),
+        config().client_number.to_string(),
+        year_start.format("%Y%m%d").to_string(),
+        config().account_length.to_string(),
+        from.format("%Y%m%d").to_string(),
+        to.format("%Y%m%d").to_string(),
+        quoted(&format!("Ruscalimat {from} - {to}")),
+        quoted(""),
+        "1".to_string(),
+        "0".to_string(),
+        "0".to_string(),
+        quoted("EUR"),
+    ];
+
+    let mut lines = vec![header.join(";"), COLUMNS.join(";")];
+    for booking in bookings {
+        let indicator = if booking.amount >= 0 { "S" } else { "H" };
+        let line = [
+            format_cents(booking.amount.abs()).replace('.', ","),
+            quoted(indicator),
+            quoted("EUR"),
+            String::new(),
+            String::new(),
+            String::new(),
+            config().balance_account.to_string(),
+            booking.counter_account.to_string(),
+            quoted(&booking.vat_key),
+            booking.date.format("%d%m").to_string(),
+            quoted(&booking.document_number),
+            quoted(""),
+            String::new(),
+            quoted(booking.text),
+        ];
+        lines.push(line.join(";"));
+    }
+
+    let mut content = lines.join("\r\n");
+    content.push_str("\r\n");
+    Ok(windows_1252(&content))
+}
+
+/// Daily totals of revenue per product type, deposits charged and deposits returned.
+/// Refunds are subtracted on the day they happened.
+async fn bookings(db: &Pool<Postgres>, from: NaiveDate, to: NaiveDate) -> Result<Vec<Booking>> {
+    let totals = sqlx::query!(
+        r#"
+        SELECT totals.day AS "day!", totals.kind AS "kind!",
+        totals.product_type AS "product_type: ProductType",
+        SUM(totals.amount)::BIGINT AS "amount!"
+        FROM (
+            SELECT (purchases.created_at::timestamptz AT TIME ZONE $1)::DATE AS day,
+            'revenue' AS kind, products.product_type, purchases.paid_price AS amount
+            FROM purchases
+            JOIN products ON products.id = purchases.product_id
+            UNION ALL
+            SELECT (purchases.refunded_at::timestamptz AT TIME ZONE $1)::DATE,
+            'revenue', products.product_type, -purchases.paid_price
+            FROM purchases
+            JOIN products ON products.id = purchases.product_id
+            WHERE purchases.refunded_at IS NOT NULL
+            UNION ALL
+            SELECT (created_at::timestamptz AT TIME ZONE $1)::DATE, 'deposit', NULL, deposit
+            FROM purchases
+            WHERE deposit > 0
+            UNION ALL
+            SELECT (refunded_at::timestamptz AT TIME ZONE $1)::DATE, 'deposit', NULL, -deposit
+            FROM purchases
+            WHERE deposit > 0 AND refunded_at IS NOT NULL
+            UNION ALL
+            SELECT (returned_at::timestamptz AT TIME ZONE $1)::DATE, 'deposit_return', NULL,
+            -amount
+            FROM deposit_returns
+        ) AS totals
+        WHERE totals.day >= $2 AND totals.day <= $3
+        GROUP BY totals.day, totals.kind, totals.product_type
+        ORDER BY totals.day, totals.kind, totals.product_type
+        "#,
+        TIMEZONE.as_str(),
+        from,
+        to
+    )
+    .fetch_all(db)
+    .await
+    .map_err(InternalServerError)?;
+
+    let mut bookings = Vec::with_capacity(totals.len());
+    let mut document_index = 0;
+    for total in totals {
+        if total.amount == 0 {
+            continue;
+        }
+
+        let (counter_account, vat_key, text) = match (total.kind.as_str(), total.product_type) {
+            ("revenue", Some(ProductType::HotDrink)) => (
+                config().hot_drink.account,
+                &config().hot_drink.vat_key,
+                "Erlöse Heißgetränke",
+            ),
+            ("revenue", Some(ProductType::ColdDrink)) => (
+                config().cold_drink.account,
+                &config().cold_drink.vat_key,
+                "Erlöse Kaltgetränke",
+            ),
+            ("deposit", _) => (config().deposit_account, &config().deposit_vat_key, "Pfand"),
+            _ => (
+                config().deposit_account,
+                &config().deposit_vat_key,
+                "Pfandrückgabe",
+            ),
+        };
+
+        document_index = match bookings.last() {
+            Some(Booking { date, .. }) if *date == total.day => document_index + 1,
+            _ => 1,
+        };
+
+        bookings.push(Booking {
+            amount: total.amount,
+            counter_account,
+            vat_key: vat_key.clone(),
+            date: total.day,
+            document_number: format!("{}-{document_index}", total.day.format("%Y%m%d")),
+            text,
+        });
+    }
+
+    Ok(bookings)
+}
+
+fn fiscal_year_start(date: NaiveDate) -> NaiveDate {
+    let month = config().fiscal_year_start_month;
+    let year = if date.month() >= month {
+        date.year()
+    } else {
+        date.year() - 1
+    };
+    NaiveDate::from_ymd_opt(year, month, 1).expect("The fiscal year start month is checked in init")
+}
+
+fn quoted(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "\"\""))
+}
+
+/// DATEV imports expect Windows-1252, which matches unicode for everything
+/// we write, except for the euro sign
+fn windows_1252(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c as u32 {
+            0x20AC => 0x80,
+            code @ (0..=0x7F | 0xA0..=0xFF) => code as u8,
+            _ => b'?',
+        })
+        .collect()
+}
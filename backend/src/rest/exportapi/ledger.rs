@@ -0,0 +1,142 @@
+//! Plain text accounting export for hledger and beancount. Every account gets its own
+//! liability account for its prepaid balance, revenue goes to one income account
+//! per product type. The output only depends on the exported data, so exporting
+//! the same period twice gives the same file.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use futures_util::TryStreamExt;
+use poem::{error::InternalServerError, Result};
+use poem_openapi::Enum;
+use sqlx::{Pool, Postgres};
+
+use crate::db::ProductType;
+
+use super::{accounting_entries, format_cents, AccountingEntry};
+
+const CURRENCY: &str = "EUR";
+const DEPOSIT_ACCOUNT: &str = "Liabilities:Deposits";
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[oai(rename_all = "lowercase")]
+pub enum LedgerFormat {
+    Hledger,
+    Beancount,
+}
+
+pub async fn export(
+    db: &Pool<Postgres>,
+    format: LedgerFormat,
+    start: Option<NaiveDateTime>,
+    end: Option<NaiveDateTime>,
+) -> Result<Vec<u8>> {
+    let entries: Vec<AccountingEntry> = accounting_entries(db, start, end)
+        .try_collect()
+        .await
+        .map_err(InternalServerError)?;
+
+    let mut journal = String::new();
+
+    if format == LedgerFormat::Beancount {
+        // beancount needs every account opened before its first use
+        let mut opened: BTreeMap<String, NaiveDate> = BTreeMap::new();
+        for entry in &entries {
+            for (account, _) in postings(entry) {
+                opened.entry(account).or_insert(entry.date.date());
+            }
+        }
+
+        writeln!(journal, "option \"operating_currency\" \"{CURRENCY}\"").unwrap();
+        journal.push('\n');
+        for (account, date) in opened {
+            writeln!(journal, "{date} open {account} {CURRENCY}").unwrap();
+        }
+        journal.push('\n');
+    }
+
+    for entry in &entries {
+        let date = entry.date.date();
+        let payee = sanitize_text(&entry.account_name);
+        let narration = sanitize_text(&narration(entry));
+
+        match format {
+            LedgerFormat::Hledger => {
+                writeln!(journal, "{date} * {payee} | {narration}").unwrap();
+                writeln!(journal, "    ; {}:{}", entry.kind, entry.id).unwrap();
+            }
+            LedgerFormat::Beancount => {
+                writeln!(journal, "{date} * \"{payee}\" \"{narration}\"").unwrap();
+                writeln!(journal, "  {}: \"{}\"", entry.kind, entry.id).unwrap();
+            }
+        }
+
+        for (account, amount) in postings(entry) {
+            writeln!(
+                journal,
+                "    {account:<50} {:>10} {CURRENCY}",
+                format_cents(amount)
+            )
+            .unwrap();
+        }
+        journal.push('\n');
+    }
+
+    Ok(journal.into_bytes())
+}
+
+/// The postings of an entry, which always add up to zero. Refunds already come
+/// with negated amounts, so they reverse their purchase.
+fn postings(entry: &AccountingEntry) -> Vec<(String, i64)> {
+    let mut postings = vec![(
+        balance_account(&entry.account_id),
+        entry.price + entry.deposit,
+    )];
+    if let Some(product_type) = entry.product_type {
+        postings.push((income_account(product_type).to_string(), -entry.price));
+    }
+    if entry.deposit != 0 {
+        postings.push((DEPOSIT_ACCOUNT.to_string(), -entry.deposit));
+    }
+    postings
+}
+
+fn narration(entry: &AccountingEntry) -> String {
+    let quantity = entry.quantity.abs();
+    let product = entry.product_name.as_deref().unwrap_or_default();
+    let deposit_type = entry.deposit_type.as_deref().unwrap_or_default();
+    match entry.kind.as_str() {
+        "purchase" => format!("{quantity}x {product}"),
+        "refund" => format!("Refund of {quantity}x {product}"),
+        _ => format!("Returned {quantity}x {deposit_type}"),
+    }
+}
+
+fn income_account(product_type: ProductType) -> &'static str {
+    match product_type {
+        ProductType::HotDrink => "Income:HotDrink",
+        ProductType::ColdDrink => "Income:ColdDrink",
+    }
+}
+
+/// Account ids are hex encoded into something both hledger and beancount accept
+/// as an account name component, so that different ids never share an account
+fn balance_account(account_id: &str) -> String {
+    let component: String = account_id
+        .bytes()
+        .map(|byte| format!("{byte:02X}"))
+        .collect();
+    format!("Liabilities:Balances:U{component}")
+}
+
+/// Keeps names from breaking out of the description
+fn sanitize_text(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '"' => '\'',
+            '|' => '/',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect()
+}
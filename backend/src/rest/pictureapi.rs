@@ -1,9 +1,24 @@
+use std::io::{BufReader, Seek};
+
+use poem::{
+    error::{BadRequest, InternalServerError},
+    Result,
+};
 use poem_openapi::{types::multipart::Upload, Multipart};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+use crate::picture;
 
 mod accountapi;
+mod files;
+mod gcapi;
+mod limits;
 mod productapi;
 
 pub use accountapi::AccountPicApi;
+pub use files::picture_file_handler;
+pub use gcapi::PictureGcApi;
 pub use productapi::ProductPicApi;
 
 #[derive(Debug, Multipart)]
@@ -11,3 +26,25 @@ pub struct FileUpload {
     file: Upload,
     filename: String,
 }
+
+/// Normalizes an uploaded picture and stores it in all sizes, see `picture::store_picture`
+async fn store_upload(
+    file_upload: FileUpload,
+    id: &str,
+    full_key: fn(&str) -> String,
+) -> Result<String> {
+    info!("Processing uploaded picture {}", file_upload.filename);
+
+    // the upload is copied to a temporary file of our own, which can be handed to the
+    // decoder, so the picture is read from disk instead of being buffered in memory
+    let temp_file = tempfile::tempfile().map_err(InternalServerError)?;
+    let mut file = tokio::fs::File::from_std(temp_file);
+    tokio::io::copy(&mut file_upload.file.into_async_read(), &mut file)
+        .await
+        .map_err(BadRequest)?;
+    file.flush().await.map_err(InternalServerError)?;
+
+    let mut file = file.into_std().await;
+    file.rewind().map_err(InternalServerError)?;
+    picture::store_picture(BufReader::new(file), id, full_key).await
+}
@@ -1,22 +1,58 @@
-use poem::error::BadRequest;
 use poem::web::Data;
 use poem::Result;
 use poem::{error::InternalServerError, http::StatusCode};
-use poem_openapi::param::Path;
-use poem_openapi::OpenApi;
+use poem_openapi::param::{Path, Query};
+use poem_openapi::payload::{Binary, Json};
+use poem_openapi::{ApiResponse, Enum, Object, OpenApi};
 use sqlx::{Pool, Postgres};
 use tracing::info;
 
 use crate::auth::JwtBearerAuth;
-use crate::s3::{self, full_account_picture_key, partial_picture_key};
+use crate::picture::{
+    self, avatar, full_account_picture_key, import, thumbnail_key, MAX_SIZE, THUMBNAIL_SIZES,
+};
 
-use super::FileUpload;
+use super::{limits::limit_uploads, store_upload, FileUpload};
 
 pub struct AccountPicApi;
 
+#[derive(Enum, Clone, Copy)]
+#[oai(rename_all = "lowercase")]
+pub enum AvatarFormat {
+    Svg,
+    Png,
+}
+
+#[derive(ApiResponse)]
+pub enum AvatarResponse {
+    #[oai(status = 200, content_type = "image/svg+xml")]
+    Svg(Binary<Vec<u8>>, #[oai(header = "Cache-Control")] String),
+    #[oai(status = 200, content_type = "image/png")]
+    Png(Binary<Vec<u8>>, #[oai(header = "Cache-Control")] String),
+    /// The account has an uploaded picture
+    #[oai(status = 302)]
+    Uploaded(
+        #[oai(header = "Location")] String,
+        #[oai(header = "Cache-Control")] String,
+    ),
+}
+
+#[derive(Object)]
+pub struct PictureImport {
+    /// Accounts without a picture, which a picture is imported for in the background
+    pub accounts: u64,
+}
+
+/// Names can change, so generated avatars aren't cached for long
+const AVATAR_CACHE_CONTROL: &str = "public, max-age=3600";
+
 #[OpenApi(prefix_path = "/picture")]
 impl AccountPicApi {
-    #[oai(path = "/account/:user_id", method = "post")]
+    #[oai(
+        path = "/account/:user_id",
+        method = "post",
+        transform = "limit_uploads"
+    )]
     pub async fn update_other_account_picture(
         &self,
         Path(user_id): Path<String>,
@@ -31,7 +67,7 @@ impl AccountPicApi {
         upload_account_picture(db, &user_id, file_upload).await
     }
 
-    #[oai(path = "/myAccount", method = "post")]
+    #[oai(path = "/myAccount", method = "post", transform = "limit_uploads")]
     pub async fn update_my_account_picture(
         &self,
 
@@ -42,6 +78,70 @@ impl AccountPicApi {
         upload_account_picture(db, &user_claims.user_id, file_upload).await
     }
 
+    /// The picture of an account, redirects to the uploaded picture in the smallest size
+    /// at least `size` pixels large, if there is one. Otherwise an avatar is generated,
+    /// the initials on a color as SVG or an identicon as PNG.
+    /// Deleted accounts have no avatar.
+    #[oai(path = "/account/:user_id/avatar", method = "get")]
+    pub async fn get_account_avatar(
+        &self,
+        Path(user_id): Path<String>,
+        Query(format): Query<Option<AvatarFormat>>,
+        Query(size): Query<Option<u32>>,
+        Data(db): Data<&Pool<Postgres>>,
+    ) -> Result<AvatarResponse> {
+        let account = sqlx::query!(
+            "SELECT name, picture FROM accounts WHERE id = $1 AND deleted_at IS NULL",
+            user_id
+        )
+        .fetch_optional(db)
+        .await
+        .map_err(InternalServerError)?
+        .ok_or(poem::Error::from_status(StatusCode::NOT_FOUND))?;
+
+        let size = size.unwrap_or(256);
+
+        if let Some(part_key) = account.picture {
+            let key = match THUMBNAIL_SIZES.iter().find(|&&thumbnail| thumbnail >= size) {
+                Some(&thumbnail) if thumbnail < MAX_SIZE => thumbnail_key(&part_key, thumbnail),
+                _ => part_key,
+            };
+            let url = picture::url(&full_account_picture_key(&key))
+                .await
+                .map_err(InternalServerError)?;
+            return Ok(AvatarResponse::Uploaded(url, "no-cache".to_string()));
+        }
+
+        match format.unwrap_or(AvatarFormat::Svg) {
+            AvatarFormat::Svg => Ok(AvatarResponse::Svg(
+                Binary(avatar::svg(&user_id, &account.name).into_bytes()),
+                AVATAR_CACHE_CONTROL.to_string(),
+            )),
+            AvatarFormat::Png => Ok(AvatarResponse::Png(
+                Binary(avatar::identicon_png(&user_id, size).map_err(InternalServerError)?),
+                AVATAR_CACHE_CONTROL.to_string(),
+            )),
+        }
+    }
+
+    /// Starts importing the Gravatar of every account without a picture, answers
+    /// before the import is done. Only one import runs at a time.
+    #[oai(path = "/accounts/import", method = "post")]
+    pub async fn import_account_pictures(
+        &self,
+        Data(db): Data<&Pool<Postgres>>,
+        JwtBearerAuth(user_claims): JwtBearerAuth,
+    ) -> Result<Json<PictureImport>> {
+        if !user_claims.is_admin() {
+            return Err(poem::Error::from_status(StatusCode::FORBIDDEN));
+        }
+
+        let accounts = import::backfill_account_pictures(db).await?;
+        Ok(Json(PictureImport {
+            accounts: accounts as u64,
+        }))
+    }
+
     #[oai(path = "/myAccount", method = "delete")]
     pub async fn delete_my_account_picture(
         &self,
@@ -69,26 +169,31 @@ impl AccountPicApi {
 async fn delete_account_picture(db: &Pool<Postgres>, user_id: &str) -> Result<()> {
     info!("Deleting account picture from user {user_id}");
 
+    // the old key is read under the row lock, so no upload can slip in between
     let account = sqlx::query!(
         r#"
-        SELECT picture FROM accounts WHERE id = $1
+        UPDATE accounts
+        SET picture = NULL
+        FROM (SELECT id, picture FROM accounts WHERE id = $1 FOR UPDATE) AS old
+        WHERE accounts.id = old.id
+        RETURNING old.picture
     "#,
         user_id
     )
-    .fetch_one(db)
+    .fetch_optional(db)
     .await
-    .map_err(|_| poem::Error::from_string("Unknown id", StatusCode::BAD_REQUEST))?;
+    .map_err(InternalServerError)?
+    .ok_or(poem::Error::from_string(
+        "Unknown id",
+        StatusCode::BAD_REQUEST,
+    ))?;
 
     let picture = account.picture.ok_or(poem::Error::from_string(
         "Account has no picture",
         StatusCode::BAD_REQUEST,
     ))?;
 
-    let full_key = full_account_picture_key(picture.as_str());
-
-    s3::delete_file(full_key.as_str())
-        .await
-        .map_err(InternalServerError)?;
+    picture::delete_picture(&picture, full_account_picture_key).await;
 
     Ok(())
 }
@@ -100,30 +205,38 @@ async fn upload_account_picture(
 ) -> Result<()> {
     assert_account_exists(db, user_id).await?;
 
-    let body = file_upload.file.into_vec().await.map_err(BadRequest)?;
-
-    let filename = file_upload.filename.as_str();
-    let part_key = partial_picture_key(filename, user_id)?;
-    let full_key = full_account_picture_key(part_key.as_str());
+    let part_key = store_upload(file_upload, user_id, full_account_picture_key).await?;
 
-    s3::upload_file(full_key.as_str(), body.into())
-        .await
-        .map_err(InternalServerError)?;
-
-    sqlx::query!(
+    let account = sqlx::query!(
         r#"
             UPDATE accounts
             SET picture = $2
-            WHERE id = $1
+            FROM (SELECT id, picture FROM accounts WHERE id = $1 FOR UPDATE) AS old
+            WHERE accounts.id = old.id
+            RETURNING old.picture
         "#,
         user_id,
         part_key
     )
-    .execute(db)
+    .fetch_optional(db)
     .await
     .map_err(InternalServerError)?;
 
-    Ok(())
+    match account {
+        Some(account) => {
+            if let Some(old_picture) = account.picture {
+                picture::delete_picture(&old_picture, full_account_picture_key).await;
+            }
+            Ok(())
+        }
+        None => {
+            picture::delete_picture(&part_key, full_account_picture_key).await;
+            Err(poem::Error::from_string(
+                "Unknown id",
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    }
 }
 
 async fn assert_account_exists(db: &Pool<Postgres>, id: &str) -> Result<()> {
@@ -0,0 +1,50 @@
+use poem::{
+    error::InternalServerError,
+    handler,
+    http::{header, StatusCode},
+    web::Path,
+    Body, Request, Response, Result,
+};
+
+use crate::storage::storage;
+
+/// Serves the stored pictures. Their keys contain a random uuid and are never
+/// overwritten, so clients can cache them for good.
+#[handler]
+pub async fn picture_file_handler(Path(key): Path<String>, req: &Request) -> Result<Response> {
+    if !key.starts_with("account/") && !key.starts_with("product/") {
+        return Err(poem::Error::from_status(StatusCode::NOT_FOUND));
+    }
+
+    let file = storage()
+        .get_file(&key)
+        .await
+        .map_err(InternalServerError)?
+        .ok_or(poem::Error::from_status(StatusCode::NOT_FOUND))?;
+
+    let mut response =
+        Response::builder().header(header::CACHE_CONTROL, "public, max-age=31536000, immutable");
+
+    if let Some(e_tag) = file.e_tag {
+        let if_none_match = req
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok());
+        let matches = if_none_match.is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == e_tag || tag.trim() == "*")
+        });
+
+        response = response.header(header::ETAG, e_tag);
+        if matches {
+            return Ok(response.status(StatusCode::NOT_MODIFIED).finish());
+        }
+    }
+
+    if let Some(content_type) = file.content_type {
+        response = response.content_type(content_type);
+    }
+
+    Ok(response.body(Body::from_async_read(file.body)))
+}
@@ -0,0 +1,30 @@
+use poem::{http::StatusCode, web::Data, Result};
+use poem_openapi::{param::Query, payload::Json, OpenApi};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    auth::JwtBearerAuth,
+    picture::gc::{self, GarbageReport},
+};
+
+pub struct PictureGcApi;
+
+#[OpenApi(prefix_path = "/picture")]
+impl PictureGcApi {
+    /// Looks for picture objects nothing points to and pictures whose object is missing.
+    /// With `remove` the objects are deleted and the pictures set to null.
+    #[oai(path = "/garbage", method = "post")]
+    pub async fn collect_picture_garbage(
+        &self,
+        Query(remove): Query<Option<bool>>,
+        Data(db): Data<&Pool<Postgres>>,
+        JwtBearerAuth(user_claims): JwtBearerAuth,
+    ) -> Result<Json<GarbageReport>> {
+        if !user_claims.is_admin() {
+            return Err(poem::Error::from_status(StatusCode::FORBIDDEN));
+        }
+
+        let report = gc::collect_garbage(db, remove.unwrap_or_default()).await?;
+        Ok(Json(report))
+    }
+}
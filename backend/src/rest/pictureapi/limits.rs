@@ -0,0 +1,153 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Error as IoError,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use poem::{
+    error::ResponseError,
+    http::{header, StatusCode},
+    Body, Endpoint, EndpointExt, IntoResponse, Middleware, Request, Response, Result,
+};
+use poem_openapi::auth::{Bearer, BearerAuthorization};
+
+use crate::{auth::check_bearer, config::SETTINGS};
+
+static MAX_UPLOAD_SIZE: Lazy<usize> =
+    Lazy::new(|| SETTINGS.get_int("uploads.max_size").unwrap() as usize);
+static RATE_LIMIT: Lazy<usize> =
+    Lazy::new(|| SETTINGS.get_int("uploads.rate_limit").unwrap() as usize);
+static RATE_LIMIT_WINDOW: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_secs(
+        SETTINGS
+            .get_int("uploads.rate_limit_window_seconds")
+            .unwrap() as u64,
+    )
+});
+
+/// Upload times of every user within the rate limit window, oldest first
+static RECENT_UPLOADS: Lazy<Mutex<HashMap<String, VecDeque<Instant>>>> =
+    Lazy::new(Default::default);
+
+/// Used as the `transform` of upload operations
+pub fn limit_uploads(ep: impl Endpoint) -> impl Endpoint {
+    ep.with(UploadLimits)
+}
+
+/// Applies the rate limit of the uploading user and cuts off request bodies larger
+/// than `uploads.max_size` while they are read, so an upload never ends up anywhere
+/// in full before it is rejected. Both happen before the body is read.
+/// The size limit covers the whole body, including the multipart framing.
+pub struct UploadLimits;
+
+impl<E: Endpoint> Middleware<E> for UploadLimits {
+    type Output = UploadLimitsEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        UploadLimitsEndpoint { inner: ep }
+    }
+}
+
+pub struct UploadLimitsEndpoint<E> {
+    inner: E,
+}
+
+#[poem::async_trait]
+impl<E: Endpoint> Endpoint for UploadLimitsEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        // the operation checks the token again, this only needs to know whose upload it is
+        let bearer = Bearer::from_request(&req)
+            .map_err(|_| poem::Error::from_status(StatusCode::UNAUTHORIZED))?;
+        let user_claims = check_bearer(bearer).await?;
+        check_rate_limit(&user_claims.user_id)?;
+
+        let max_size = *MAX_UPLOAD_SIZE;
+
+        let content_length = req
+            .header(header::CONTENT_LENGTH)
+            .and_then(|value| value.parse::<usize>().ok());
+        if content_length.is_some_and(|length| length > max_size) {
+            return Err(too_large(max_size));
+        }
+
+        // the body is cut off with an IO error, which the multipart parser turns into
+        // a generic parse error, so the flag tells the two apart afterwards
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let mut received = 0;
+        let body = req.take_body().into_bytes_stream().map({
+            let exceeded = exceeded.clone();
+            move |chunk| {
+                let chunk = chunk?;
+                received += chunk.len();
+                if received > max_size {
+                    exceeded.store(true, Ordering::Relaxed);
+                    return Err(IoError::other("Upload too large"));
+                }
+                Ok(chunk)
+            }
+        });
+        req.set_body(Body::from_bytes_stream(body));
+
+        let res = self.inner.call(req).await;
+        if exceeded.load(Ordering::Relaxed) {
+            return Err(too_large(max_size));
+        }
+        res.map(IntoResponse::into_response)
+    }
+}
+
+fn too_large(max_size: usize) -> poem::Error {
+    poem::Error::from_string(
+        format!("Uploads can be at most {max_size} bytes"),
+        StatusCode::PAYLOAD_TOO_LARGE,
+    )
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Too many picture uploads, try again in {} seconds", retry_after.as_secs() + 1)]
+pub struct RateLimited {
+    retry_after: Duration,
+}
+
+impl ResponseError for RateLimited {
+    fn status(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+}
+
+/// Allows every user `uploads.rate_limit` picture uploads per
+/// `uploads.rate_limit_window_seconds`, counting this one
+fn check_rate_limit(user_id: &str) -> Result<(), RateLimited> {
+    let now = Instant::now();
+    let window = *RATE_LIMIT_WINDOW;
+    let mut recent_uploads = RECENT_UPLOADS.lock().unwrap();
+
+    // users without uploads in the window are forgotten, so this doesn't grow forever
+    recent_uploads.retain(|_, uploads| {
+        while uploads
+            .front()
+            .is_some_and(|upload| now.duration_since(*upload) >= window)
+        {
+            uploads.pop_front();
+        }
+        !uploads.is_empty()
+    });
+
+    let uploads = recent_uploads.entry(user_id.to_string()).or_default();
+    if let Some(oldest) = uploads.front().filter(|_| uploads.len() >= *RATE_LIMIT) {
+        return Err(RateLimited {
+            retry_after: window.saturating_sub(now.duration_since(*oldest)),
+        });
+    }
+
+    uploads.push_back(now);
+    Ok(())
+}
@@ -1,9 +1,5 @@
-use poem::{
-    error::{BadRequest, InternalServerError},
-    web::Data,
-    Result,
-};
-use poem_openapi::{param::Path, OpenApi};
+use poem::{error::InternalServerError, web::Data, Result};
+use poem_openapi::{param::Path, payload::Json, Object, OpenApi};
 use reqwest::StatusCode;
 use sqlx::{Pool, Postgres};
 use tracing::info;
@@ -11,16 +7,24 @@ use tracing::info;
 use crate::{
     auth::JwtBearerAuth,
     db::PrimaryKey,
-    s3::{self, full_product_picture_key, partial_picture_key},
+    events,
+    picture::{self, full_product_picture_key, gallery},
 };
 
-use super::FileUpload;
+use super::{limits::limit_uploads, store_upload, FileUpload};
+
+#[derive(Object)]
+pub struct AddedProductImage {
+    pub id: PrimaryKey,
+    pub position: i32,
+}
 
 pub struct ProductPicApi;
 
 #[OpenApi(prefix_path = "/picture/product")]
 impl ProductPicApi {
-    #[oai(path = "/:id", method = "post")]
+    /// Replaces the primary image of the product, or adds one to an empty gallery
+    #[oai(path = "/:product_id", method = "post", transform = "limit_uploads")]
     pub async fn update_product_picture(
         &self,
         Path(product_id): Path<PrimaryKey>,
@@ -36,33 +40,68 @@ impl ProductPicApi {
 
         assert_product_exists(db, product_id).await?;
 
-        let body = file_upload.file.into_vec().await.map_err(BadRequest)?;
-
-        let filename = file_upload.filename.as_str();
-        let part_key = partial_picture_key(filename, &product_id.to_string())?;
-        let full_key = full_product_picture_key(part_key.as_str());
+        let part_key = store_upload(
+            file_upload,
+            &product_id.to_string(),
+            full_product_picture_key,
+        )
+        .await?;
 
-        s3::upload_file(full_key.as_str(), body.into())
+        let mut tx = db.begin().await.map_err(InternalServerError)?;
+        if !gallery::lock_product(&mut tx, product_id)
             .await
-            .map_err(InternalServerError)?;
+            .map_err(InternalServerError)?
+        {
+            picture::delete_picture(&part_key, full_product_picture_key).await;
+            return Err(unknown_id());
+        }
 
-        sqlx::query!(
+        let replaced = sqlx::query!(
             r#"
-            UPDATE products
+            UPDATE product_images
             SET picture = $2
-            WHERE id = $1
+            FROM (
+                SELECT id, picture FROM product_images
+                WHERE product_id = $1
+                ORDER BY position
+                LIMIT 1
+            ) AS old
+            WHERE product_images.id = old.id
+            RETURNING old.picture
         "#,
             product_id,
             part_key
         )
-        .execute(db)
+        .fetch_optional(&mut *tx)
         .await
         .map_err(InternalServerError)?;
 
+        if replaced.is_none() {
+            sqlx::query!(
+                "INSERT INTO product_images (product_id, picture, position) VALUES ($1, $2, 0)",
+                product_id,
+                part_key
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(InternalServerError)?;
+        }
+
+        gallery::sync_primary(&mut tx, product_id)
+            .await
+            .map_err(InternalServerError)?;
+        tx.commit().await.map_err(InternalServerError)?;
+        events::publish_product_update(db, product_id).await;
+
+        if let Some(replaced) = replaced {
+            picture::delete_picture(&replaced.picture, full_product_picture_key).await;
+        }
+
         Ok(())
     }
 
-    #[oai(path = "/:id", method = "delete")]
+    /// Removes the primary image, the next one in the gallery takes its place
+    #[oai(path = "/:product_id", method = "delete")]
     pub async fn remove_product_picture(
         &self,
         Path(product_id): Path<PrimaryKey>,
@@ -74,26 +113,215 @@ impl ProductPicApi {
         }
         info!("Deleting product picture for {product_id}");
 
-        let account = sqlx::query!(
+        // the primary image is picked under the row lock, so no upload can slip in between
+        let mut tx = db.begin().await.map_err(InternalServerError)?;
+        if !gallery::lock_product(&mut tx, product_id)
+            .await
+            .map_err(InternalServerError)?
+        {
+            return Err(unknown_id());
+        }
+
+        let image = sqlx::query!(
             r#"
-        SELECT picture FROM products WHERE id = $1
-    "#,
+            DELETE FROM product_images
+            WHERE id = (
+                SELECT id FROM product_images
+                WHERE product_id = $1
+                ORDER BY position
+                LIMIT 1
+            )
+            RETURNING picture
+        "#,
             product_id
         )
-        .fetch_one(db)
+        .fetch_optional(&mut *tx)
         .await
-        .map_err(|_| poem::Error::from_string("Unknown id", StatusCode::BAD_REQUEST))?;
-
-        let picture = account.picture.ok_or(poem::Error::from_string(
+        .map_err(InternalServerError)?
+        .ok_or(poem::Error::from_string(
             "Product has no picture",
             StatusCode::BAD_REQUEST,
         ))?;
 
-        let full_key = full_product_picture_key(picture.as_str());
+        gallery::sync_primary(&mut tx, product_id)
+            .await
+            .map_err(InternalServerError)?;
+        tx.commit().await.map_err(InternalServerError)?;
+        events::publish_product_update(db, product_id).await;
+
+        picture::delete_picture(&image.picture, full_product_picture_key).await;
+
+        Ok(())
+    }
 
-        s3::delete_file(full_key.as_str())
+    /// Adds an image to the end of the gallery, it becomes the primary one if it is the first
+    #[oai(
+        path = "/:product_id/images",
+        method = "post",
+        transform = "limit_uploads"
+    )]
+    pub async fn add_product_image(
+        &self,
+        Path(product_id): Path<PrimaryKey>,
+        Data(db): Data<&Pool<Postgres>>,
+        JwtBearerAuth(user_claims): JwtBearerAuth,
+        file_upload: FileUpload,
+    ) -> Result<Json<AddedProductImage>> {
+        if !user_claims.is_admin() {
+            return Err(poem::Error::from_status(StatusCode::FORBIDDEN));
+        }
+
+        info!("Adding product image for {product_id}");
+
+        assert_product_exists(db, product_id).await?;
+
+        let part_key = store_upload(
+            file_upload,
+            &product_id.to_string(),
+            full_product_picture_key,
+        )
+        .await?;
+
+        let mut tx = db.begin().await.map_err(InternalServerError)?;
+        if !gallery::lock_product(&mut tx, product_id)
+            .await
+            .map_err(InternalServerError)?
+        {
+            picture::delete_picture(&part_key, full_product_picture_key).await;
+            return Err(unknown_id());
+        }
+
+        let image = sqlx::query_as!(
+            AddedProductImage,
+            r#"
+            INSERT INTO product_images (product_id, picture, position)
+            SELECT $1, $2, COALESCE(MAX(position) + 1, 0)
+            FROM product_images
+            WHERE product_id = $1
+            RETURNING id, position
+        "#,
+            product_id,
+            part_key
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(InternalServerError)?;
+
+        gallery::sync_primary(&mut tx, product_id)
             .await
             .map_err(InternalServerError)?;
+        tx.commit().await.map_err(InternalServerError)?;
+        events::publish_product_update(db, product_id).await;
+
+        Ok(Json(image))
+    }
+
+    /// Puts the gallery into the order of `image_ids`, which has to contain every
+    /// image of the product exactly once. The first one becomes the primary image.
+    #[oai(path = "/:product_id/images/order", method = "put")]
+    pub async fn reorder_product_images(
+        &self,
+        Path(product_id): Path<PrimaryKey>,
+        Data(db): Data<&Pool<Postgres>>,
+        JwtBearerAuth(user_claims): JwtBearerAuth,
+        Json(image_ids): Json<Vec<PrimaryKey>>,
+    ) -> Result<()> {
+        if !user_claims.is_admin() {
+            return Err(poem::Error::from_status(StatusCode::FORBIDDEN));
+        }
+        info!("Reordering product images for {product_id}");
+
+        let mut tx = db.begin().await.map_err(InternalServerError)?;
+        if !gallery::lock_product(&mut tx, product_id)
+            .await
+            .map_err(InternalServerError)?
+        {
+            return Err(unknown_id());
+        }
+
+        let mut current_ids: Vec<PrimaryKey> = sqlx::query_scalar!(
+            "SELECT id FROM product_images WHERE product_id = $1",
+            product_id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(InternalServerError)?;
+
+        let mut new_ids = image_ids.clone();
+        current_ids.sort_unstable();
+        new_ids.sort_unstable();
+        if current_ids != new_ids {
+            return Err(poem::Error::from_string(
+                "The order has to contain every image of the product exactly once",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE product_images
+            SET position = new.position - 1
+            FROM UNNEST($2::BIGINT[]) WITH ORDINALITY AS new(id, position)
+            WHERE product_images.id = new.id AND product_images.product_id = $1
+        "#,
+            product_id,
+            &image_ids
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(InternalServerError)?;
+
+        gallery::sync_primary(&mut tx, product_id)
+            .await
+            .map_err(InternalServerError)?;
+        tx.commit().await.map_err(InternalServerError)?;
+        events::publish_product_update(db, product_id).await;
+
+        Ok(())
+    }
+
+    /// Removes an image from the gallery, if it was the primary one the next takes its place
+    #[oai(path = "/:product_id/images/:image_id", method = "delete")]
+    pub async fn remove_product_image(
+        &self,
+        Path(product_id): Path<PrimaryKey>,
+        Path(image_id): Path<PrimaryKey>,
+        Data(db): Data<&Pool<Postgres>>,
+        JwtBearerAuth(user_claims): JwtBearerAuth,
+    ) -> Result<()> {
+        if !user_claims.is_admin() {
+            return Err(poem::Error::from_status(StatusCode::FORBIDDEN));
+        }
+        info!("Deleting product image {image_id} of {product_id}");
+
+        let mut tx = db.begin().await.map_err(InternalServerError)?;
+        if !gallery::lock_product(&mut tx, product_id)
+            .await
+            .map_err(InternalServerError)?
+        {
+            return Err(unknown_id());
+        }
+
+        let image = sqlx::query!(
+            "DELETE FROM product_images WHERE id = $1 AND product_id = $2 RETURNING picture",
+            image_id,
+            product_id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(InternalServerError)?
+        .ok_or(poem::Error::from_string(
+            "Unknown image id",
+            StatusCode::BAD_REQUEST,
+        ))?;
+
+        gallery::sync_primary(&mut tx, product_id)
+            .await
+            .map_err(InternalServerError)?;
+        tx.commit().await.map_err(InternalServerError)?;
+        events::publish_product_update(db, product_id).await;
+
+        picture::delete_picture(&image.picture, full_product_picture_key).await;
 
         Ok(())
     }
@@ -103,6 +331,10 @@ async fn assert_product_exists(db: &Pool<Postgres>, product_id: PrimaryKey) -> R
     sqlx::query!("SELECT id FROM products WHERE id = $1", product_id)
         .fetch_one(db)
         .await
-        .map_err(|_| poem::Error::from_string("Unknown id", StatusCode::BAD_REQUEST))?;
+        .map_err(|_| unknown_id())?;
     Ok(())
 }
+
+fn unknown_id() -> poem::Error {
+    poem::Error::from_string("Unknown id", StatusCode::BAD_REQUEST)
+}
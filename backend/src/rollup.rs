@@ -0,0 +1,189 @@
+//! Daily rollups of the purchases, which the statistics read instead of going
+//! through every purchase. They are kept up to date as purchases and refunds
+//! happen, in the same transaction, and can be rebuilt from the purchases at any time.
+
+use chrono::{Days, NaiveDate, NaiveDateTime, NaiveTime};
+use once_cell::sync::Lazy;
+use sqlx::{PgConnection, Pool, Postgres};
+use tracing::info;
+
+use crate::{config::SETTINGS, db::PrimaryKey};
+
+/// The rollups are split into days in the default timezone of the statistics
+pub static DEFAULT_TIMEZONE: Lazy<String> =
+    Lazy::new(|| SETTINGS.get_string("statistics.timezone").unwrap());
+
+/// A time range split into the whole days, which are read from the rollups,
+/// and the partial days before and after them, which are read from the purchases.
+/// All ranges have an inclusive start and an exclusive end.
+pub struct RollupRange {
+    pub days: (NaiveDate, NaiveDate),
+    pub head: (NaiveDateTime, NaiveDateTime),
+    pub tail: (NaiveDateTime, NaiveDateTime),
+}
+
+impl RollupRange {
+    /// Splits the range from `from` to `to`, which are wall clock times in `timezone`.
+    /// The rollups only work for the timezone they were built in, for any other
+    /// the whole range is read from the purchases.
+    pub fn new(from: NaiveDateTime, to: NaiveDateTime, timezone: &str) -> RollupRange {
+        let first_day = if from.time() == NaiveTime::MIN {
+            from.date()
+        } else {
+            from.date() + Days::new(1)
+        };
+        let end_day = to.date();
+
+        if timezone != DEFAULT_TIMEZONE.as_str() || first_day >= end_day {
+            return RollupRange {
+                days: (first_day, first_day),
+                head: (from, to),
+                tail: (to, to),
+            };
+        }
+
+        RollupRange {
+            days: (first_day, end_day),
+            head: (from, first_day.and_time(NaiveTime::MIN)),
+            tail: (end_day.and_time(NaiveTime::MIN), to),
+        }
+    }
+}
+
+/// Adds a new purchase to the rollups, needs to run in the transaction inserting it
+pub async fn add_purchase(conn: &mut PgConnection, purchase_id: PrimaryKey) -> sqlx::Result<()> {
+    apply_purchase(conn, purchase_id, 1).await
+}
+
+/// Takes a refunded purchase out of the rollups, on the day it was bought.
+/// Needs to run in the transaction refunding it.
+pub async fn remove_purchase(conn: &mut PgConnection, purchase_id: PrimaryKey) -> sqlx::Result<()> {
+    apply_purchase(conn, purchase_id, -1).await
+}
+
+async fn apply_purchase(
+    conn: &mut PgConnection,
+    purchase_id: PrimaryKey,
+    sign: i64,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO daily_product_sales (day, product_id, units, revenue)
+        SELECT (created_at::timestamptz AT TIME ZONE $2)::DATE, product_id,
+        $3::BIGINT * quantity, $3::BIGINT * paid_price
+        FROM purchases
+        WHERE id = $1 AND created_at IS NOT NULL
+        ON CONFLICT (day, product_id) DO UPDATE
+        SET units = daily_product_sales.units + EXCLUDED.units,
+        revenue = daily_product_sales.revenue + EXCLUDED.revenue
+        "#,
+        purchase_id,
+        DEFAULT_TIMEZONE.as_str(),
+        sign
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO daily_account_sales (day, account_id, product_type, units)
+        SELECT (purchases.created_at::timestamptz AT TIME ZONE $2)::DATE, purchases.account_id,
+        products.product_type, $3::BIGINT * purchases.quantity
+        FROM purchases
+        JOIN products ON products.id = purchases.product_id
+        WHERE purchases.id = $1 AND purchases.created_at IS NOT NULL
+        ON CONFLICT (day, account_id, product_type) DO UPDATE
+        SET units = daily_account_sales.units + EXCLUDED.units
+        "#,
+        purchase_id,
+        DEFAULT_TIMEZONE.as_str(),
+        sign
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Builds the rollups if there are none yet, like after they were added or cleared,
+/// or if they were built in another timezone than the configured one. Anything else
+/// is kept up to date, so it doesn't need a rebuild on every startup.
+pub async fn rebuild_if_stale(db: &Pool<Postgres>) -> sqlx::Result<()> {
+    let stale = sqlx::query_scalar!(
+        r#"
+        SELECT (NOT EXISTS (SELECT 1 FROM daily_product_sales)
+            AND NOT EXISTS (SELECT 1 FROM daily_account_sales))
+        OR NOT EXISTS (SELECT 1 FROM rollup_meta WHERE timezone = $1) AS "stale!"
+        "#,
+        DEFAULT_TIMEZONE.as_str()
+    )
+    .fetch_one(db)
+    .await?;
+
+    if stale {
+        rebuild(db).await?;
+    }
+    Ok(())
+}
+
+/// Throws the rollups away and builds them again from the purchases.
+/// Purchases and refunds wait until this is done.
+pub async fn rebuild(db: &Pool<Postgres>) -> sqlx::Result<()> {
+    info!(
+        "Rebuilding statistics rollups in {}",
+        DEFAULT_TIMEZONE.as_str()
+    );
+
+    let mut tx = db.begin().await?;
+
+    sqlx::query!("LOCK TABLE purchases IN SHARE MODE")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM daily_product_sales")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM daily_account_sales")
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO daily_product_sales (day, product_id, units, revenue)
+        SELECT (created_at::timestamptz AT TIME ZONE $1)::DATE, product_id,
+        SUM(quantity), SUM(paid_price)
+        FROM purchases
+        WHERE NOT refunded AND created_at IS NOT NULL
+        GROUP BY 1, 2
+        "#,
+        DEFAULT_TIMEZONE.as_str()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO daily_account_sales (day, account_id, product_type, units)
+        SELECT (purchases.created_at::timestamptz AT TIME ZONE $1)::DATE, purchases.account_id,
+        products.product_type, SUM(purchases.quantity)
+        FROM purchases
+        JOIN products ON products.id = purchases.product_id
+        WHERE NOT purchases.refunded AND purchases.created_at IS NOT NULL
+        GROUP BY 1, 2, 3
+        "#,
+        DEFAULT_TIMEZONE.as_str()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO rollup_meta (timezone) VALUES ($1)
+        ON CONFLICT (id) DO UPDATE SET timezone = EXCLUDED.timezone
+        "#,
+        DEFAULT_TIMEZONE.as_str()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await
+}
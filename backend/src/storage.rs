@@ -0,0 +1,102 @@
+//! Where uploaded files end up. `storage.backend` in the config picks S3, a local
+//! directory, which is enough for small deployments with a mounted volume, or memory,
+//! which needs nothing at all but forgets everything on restart.
+
+use std::{pin::Pin, sync::OnceLock, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::io::AsyncRead;
+use tracing::info;
+
+use crate::config::SETTINGS;
+
+mod local;
+mod memory;
+mod s3;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    /// Boxed, the S3 errors are huge
+    #[error("S3 error: {0}")]
+    S3(Box<aws_sdk_s3::Error>),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid key {0}")]
+    InvalidKey(String),
+    #[error("Invalid storage config: {0}")]
+    Config(String),
+}
+
+impl From<aws_sdk_s3::Error> for StorageError {
+    fn from(err: aws_sdk_s3::Error) -> Self {
+        StorageError::S3(Box::new(err))
+    }
+}
+
+pub type Result<T, E = StorageError> = std::result::Result<T, E>;
+
+pub struct StoredFile {
+    pub body: Pin<Box<dyn AsyncRead + Send>>,
+    pub content_type: Option<String>,
+    pub e_tag: Option<String>,
+}
+
+pub struct StoredObject {
+    /// The full key
+    pub key: String,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// All keys here are full keys, not part keys
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn upload_file(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<()>;
+
+    /// `None` if there is no file with that key
+    async fn get_file(&self, key: &str) -> Result<Option<StoredFile>>;
+
+    /// Deleting a file that doesn't exist is not an error
+    async fn delete_file(&self, key: &str) -> Result<()>;
+
+    /// All files whose key starts with `prefix`
+    async fn list_files(&self, prefix: &str) -> Result<Vec<StoredObject>>;
+
+    /// A url to fetch the file straight from the storage, which stops working after
+    /// `expires_in`. `None` if the storage can't be reached from outside.
+    async fn presigned_url(&self, key: &str, expires_in: Duration) -> Result<Option<String>>;
+}
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+
+pub async fn init() -> Result<()> {
+    let backend = SETTINGS
+        .get_string("storage.backend")
+        .map_err(|err| StorageError::Config(err.to_string()))?;
+    info!("Using {backend} storage");
+
+    let storage: Box<dyn Storage> = match backend.as_str() {
+        "s3" => Box::new(s3::S3Storage::new().await?),
+        "local" => {
+            let path = SETTINGS
+                .get_string("storage.local.path")
+                .map_err(|err| StorageError::Config(err.to_string()))?;
+            Box::new(local::LocalStorage::new(path.into()).await?)
+        }
+        "memory" => Box::<memory::MemoryStorage>::default(),
+        backend => {
+            return Err(StorageError::Config(format!(
+                "Unknown storage.backend {backend}, expected s3, local or memory"
+            )))
+        }
+    };
+
+    if STORAGE.set(storage).is_err() {
+        panic!("Storage initialized twice");
+    }
+    Ok(())
+}
+
+pub fn storage() -> &'static dyn Storage {
+    STORAGE.get().expect("Storage isn't initialized").as_ref()
+}
@@ -0,0 +1,139 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::fs;
+use tracing::info;
+use uuid::Uuid;
+
+use super::{Result, Storage, StorageError, StoredFile, StoredObject};
+
+/// Stores every file under its key in a directory, keys with slashes end up in subdirectories
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub async fn new(root: PathBuf) -> Result<LocalStorage> {
+        fs::create_dir_all(&root).await?;
+        Ok(LocalStorage { root })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let valid = !key.is_empty()
+            && !key.contains('\\')
+            && key
+                .split('/')
+                .all(|part| !part.is_empty() && part != "." && part != "..");
+        if !valid {
+            return Err(StorageError::InvalidKey(key.to_string()));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+/// Nothing stores the content type next to the file, the extension has to do
+fn content_type(key: &str) -> Option<String> {
+    let content_type = match Path::new(key).extension()?.to_str()? {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        _ => return None,
+    };
+    Some(content_type.to_string())
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn upload_file(&self, key: &str, body: Vec<u8>, _content_type: &str) -> Result<()> {
+        info!("Uploading file {key}");
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // written next to the target first, so readers never see half a file
+        let temp_path = path.with_file_name(format!(".{}.tmp", Uuid::new_v4()));
+        fs::write(&temp_path, body).await?;
+        fs::rename(&temp_path, &path).await?;
+
+        Ok(())
+    }
+
+    async fn get_file(&self, key: &str) -> Result<Option<StoredFile>> {
+        let file = match fs::File::open(self.path(key)?).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let metadata = file.metadata().await?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Ok(Some(StoredFile {
+            body: Box::pin(file),
+            content_type: content_type(key),
+            e_tag: Some(format!(
+                "\"{:x}-{:x}\"",
+                metadata.len(),
+                modified.as_nanos()
+            )),
+        }))
+    }
+
+    async fn delete_file(&self, key: &str) -> Result<()> {
+        info!("Deleting file {key}");
+        match fs::remove_file(self.path(key)?).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list_files(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        let mut objects = Vec::new();
+        let mut directories = vec![self.root.clone()];
+
+        while let Some(directory) = directories.pop() {
+            let mut entries = fs::read_dir(&directory).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    directories.push(entry.path());
+                    continue;
+                }
+
+                let Ok(relative) = entry.path().strip_prefix(&self.root).map(Path::to_owned) else {
+                    continue;
+                };
+                let key = relative
+                    .components()
+                    .map(|part| part.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                // leftovers of interrupted uploads don't count
+                if !key.starts_with(prefix) || key.ends_with(".tmp") {
+                    continue;
+                }
+
+                objects.push(StoredObject {
+                    key,
+                    last_modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+                });
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn presigned_url(&self, _key: &str, _expires_in: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
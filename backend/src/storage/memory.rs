@@ -0,0 +1,73 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    io::Cursor,
+    sync::RwLock,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use super::{Result, Storage, StoredFile, StoredObject};
+
+struct MemoryFile {
+    body: Vec<u8>,
+    content_type: String,
+    e_tag: String,
+    last_modified: DateTime<Utc>,
+}
+
+/// Keeps all files in memory, they are gone after a restart
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: RwLock<BTreeMap<String, MemoryFile>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn upload_file(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<()> {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+
+        let file = MemoryFile {
+            e_tag: format!("\"{:x}\"", hasher.finish()),
+            body,
+            content_type: content_type.to_string(),
+            last_modified: Utc::now(),
+        };
+        self.files.write().unwrap().insert(key.to_string(), file);
+
+        Ok(())
+    }
+
+    async fn get_file(&self, key: &str) -> Result<Option<StoredFile>> {
+        let files = self.files.read().unwrap();
+        Ok(files.get(key).map(|file| StoredFile {
+            body: Box::pin(Cursor::new(file.body.clone())),
+            content_type: Some(file.content_type.clone()),
+            e_tag: Some(file.e_tag.clone()),
+        }))
+    }
+
+    async fn delete_file(&self, key: &str) -> Result<()> {
+        self.files.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn list_files(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        let files = self.files.read().unwrap();
+        Ok(files
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, file)| StoredObject {
+                key: key.clone(),
+                last_modified: Some(file.last_modified),
+            })
+            .collect())
+    }
+
+    async fn presigned_url(&self, _key: &str, _expires_in: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
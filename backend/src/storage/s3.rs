@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use aws_credential_types::{credential_fn::provide_credentials_fn, Credentials};
+use aws_sdk_s3::{
+    config::Region, operation::create_bucket::CreateBucketError, presigning::PresigningConfig,
+    primitives::ByteStream, types::CreateBucketConfiguration, Client,
+};
+
+use chrono::{TimeZone, Utc};
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use tracing::info;
+
+use crate::{config::SETTINGS, metrics::S3_ERRORS};
+
+use super::{Result, Storage, StorageError, StoredFile, StoredObject};
+
+static ACCESS_KEY: Lazy<String> = Lazy::new(|| SETTINGS.get_string("s3.accesskey").unwrap());
+static SECRET_KEY: Lazy<String> = Lazy::new(|| SETTINGS.get_string("s3.secretkey").unwrap());
+
+pub struct S3Storage {
+    client: Client,
+    bucket_name: String,
+}
+
+impl S3Storage {
+    pub async fn new() -> Result<S3Storage> {
+        let setting = |key: &str| {
+            SETTINGS
+                .get_string(key)
+                .map_err(|err| StorageError::Config(err.to_string()))
+        };
+        let region = Region::new(setting("s3.region")?);
+
+        let config = aws_config::from_env()
+            .endpoint_url(setting("s3.url")?)
+            .credentials_provider(provide_credentials_fn(credentials_provider))
+            .region(region)
+            .load()
+            .await;
+
+        let s3_config: aws_sdk_s3::Config = (&config).into();
+        let s3_config = s3_config.to_builder().force_path_style(true).build();
+
+        let storage = S3Storage {
+            client: aws_sdk_s3::Client::from_conf(s3_config),
+            bucket_name: setting("s3.bucketname")?,
+        };
+
+        storage.create_bucket().await?;
+
+        Ok(storage)
+    }
+
+    async fn create_bucket(&self) -> Result<()> {
+        info!("Trying to create bucket {}", self.bucket_name);
+        let cfg = CreateBucketConfiguration::builder().build();
+
+        let result = self
+            .client
+            .create_bucket()
+            .create_bucket_configuration(cfg)
+            .bucket(&self.bucket_name)
+            .send()
+            .await;
+
+        if let Err(err) = result {
+            S3_ERRORS.with_label_values(&["create_bucket"]).inc();
+            match err.into_service_error() {
+                CreateBucketError::BucketAlreadyExists(_)
+                | CreateBucketError::BucketAlreadyOwnedByYou(_) => {}
+                err => return Err(aws_sdk_s3::Error::from(err).into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn credentials_provider() -> aws_credential_types::provider::Result {
+    Ok(Credentials::from_keys(
+        ACCESS_KEY.clone(),
+        SECRET_KEY.clone(),
+        None,
+    ))
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn upload_file(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<()> {
+        info!("Uploading file {key}");
+        self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .body(ByteStream::from(body))
+            .content_type(content_type)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)
+            .inspect_err(|_| S3_ERRORS.with_label_values(&["put_object"]).inc())?;
+
+        Ok(())
+    }
+
+    async fn get_file(&self, key: &str) -> Result<Option<StoredFile>> {
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .send()
+            .await;
+
+        match result {
+            Ok(output) => Ok(Some(StoredFile {
+                body: Box::pin(output.body.into_async_read()),
+                content_type: output.content_type,
+                e_tag: output.e_tag,
+            })),
+            Err(err) => {
+                let err = err.into_service_error();
+                if err.is_no_such_key() {
+                    return Ok(None);
+                }
+                S3_ERRORS.with_label_values(&["get_object"]).inc();
+                Err(aws_sdk_s3::Error::from(err).into())
+            }
+        }
+    }
+
+    async fn delete_file(&self, key: &str) -> Result<()> {
+        info!("Deleting file {key}");
+        self.client
+            .delete_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)
+            .inspect_err(|_| S3_ERRORS.with_label_values(&["delete_object"]).inc())?;
+
+        Ok(())
+    }
+
+    async fn list_files(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket_name)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+
+        let mut objects = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page
+                .map_err(aws_sdk_s3::Error::from)
+                .inspect_err(|_| S3_ERRORS.with_label_values(&["list_objects"]).inc())?;
+            objects.extend(
+                page.contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|object| {
+                        Some(StoredObject {
+                            last_modified: object
+                                .last_modified
+                                .and_then(|time| Utc.timestamp_opt(time.secs(), 0).single()),
+                            key: object.key?,
+                        })
+                    }),
+            );
+        }
+
+        Ok(objects)
+    }
+
+    async fn presigned_url(&self, key: &str, expires_in: Duration) -> Result<Option<String>> {
+        let expires_in = PresigningConfig::expires_in(expires_in)
+            .map_err(|err| StorageError::Config(err.to_string()))?;
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .presigned(expires_in)
+            .await
+            .map_err(aws_sdk_s3::Error::from)
+            .inspect_err(|_| S3_ERRORS.with_label_values(&["presign_get_object"]).inc())?;
+
+        Ok(Some(request.uri().to_string()))
+    }
+}